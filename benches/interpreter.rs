@@ -1,14 +1,14 @@
-/// Benchmarks for interpreter
+//! Benchmarks for interpreter
 
 #[macro_use]
 extern crate criterion;
 extern crate glitter_lang;
 
 use glitter_lang::ast::{Color, CompleteStyle, Delimiter, Expression, Name, Style, Tree};
-use glitter_lang::git::Stats;
-use glitter_lang::interpreter::Interpreter;
+use glitter_lang::git::{Operation, Stats};
+use glitter_lang::interpreter::{Escapes, Interpreter};
 
-use criterion::{Bencher, Criterion, Fun};
+use criterion::{Bencher, BenchmarkId, Criterion};
 
 fn stats() -> Stats {
     Stats {
@@ -25,6 +25,15 @@ fn stats() -> Stats {
         stashes: 1,
         branch: "master".to_string(),
         remote: "origin/master".to_string(),
+        operation: Operation::Rebase,
+        rebase_step: 1,
+        rebase_total: 1,
+        rebase_onto: "abc123ef".to_string(),
+        insertions: 1,
+        insertions_staged: 1,
+        deletions: 1,
+        deletions_staged: 1,
+        missing: Default::default(),
     }
 }
 
@@ -54,7 +63,7 @@ fn empty_stats(c: &mut Criterion) {
             },
         ]),
     }]);
-    let mut interpreter = Interpreter::new(empty, true, true);
+    let mut interpreter = Interpreter::new(empty, true, Escapes::Bash);
 
     c.bench_function("default stats \"[MARD]\"", move |b| {
         let mut out = Vec::with_capacity(128);
@@ -70,7 +79,7 @@ fn real_world(c: &mut Criterion) {
 
     let tree = parse(r"[#g*(b)#r(B(#~('..')))#w(\(#~*(+('↑')-('↓')))<#g(MARD)#r(maud)>{#m*_(h('@'))})]' '#b*('\w')'\n '").expect("failed to parse example");
 
-    let mut i = Interpreter::new(stats(), true, true);
+    let mut i = Interpreter::new(stats(), true, Escapes::Bash);
     c.bench_function("Real world \"$GIT_FMT\" example", move |b| {
         let mut out = Vec::with_capacity(256);
         b.iter(|| {
@@ -103,7 +112,7 @@ fn nested_named(c: &mut Criterion) {
     macro_rules! depth {
         ($($tail:tt),+) => {{
             |b: &mut Bencher, s: &Stats| {
-                let mut interpreter = Interpreter::new(s.clone(), true, true);
+                let mut interpreter = Interpreter::new(s.clone(), true, Escapes::Bash);
                 // Use passed tokens as the Name type in each subtree
                 let e = tree![$($tail),+];
                 let mut out = Vec::with_capacity(128);
@@ -115,20 +124,22 @@ fn nested_named(c: &mut Criterion) {
         }};
     }
 
-    c.bench_functions(
-        "nested named",
-        vec![
-            Fun::new("depth 1", depth![Modified]),
-            Fun::new("depth 2", depth![Modified, Added]),
-            Fun::new("depth 3", depth![Modified, Added, Untracked]),
-            Fun::new("depth 4", depth![Modified, Added, Untracked, Deleted]),
-            Fun::new(
-                "depth 5",
-                depth![Modified, Added, Untracked, Deleted, Branch],
-            ),
-        ],
-        stats(),
+    let mut group = c.benchmark_group("nested named");
+    let stats = stats();
+    group.bench_with_input("depth 1", &stats, depth![Modified]);
+    group.bench_with_input("depth 2", &stats, depth![Modified, Added]);
+    group.bench_with_input("depth 3", &stats, depth![Modified, Added, Untracked]);
+    group.bench_with_input(
+        "depth 4",
+        &stats,
+        depth![Modified, Added, Untracked, Deleted],
     );
+    group.bench_with_input(
+        "depth 5",
+        &stats,
+        depth![Modified, Added, Untracked, Deleted, Branch],
+    );
+    group.finish();
 }
 
 fn tree_length(c: &mut Criterion) {
@@ -147,7 +158,7 @@ fn tree_length(c: &mut Criterion) {
                     .collect(),
                 );
 
-                let mut i = Interpreter::new(s.clone(), true, true);
+                let mut i = Interpreter::new(s.clone(), true, Escapes::Bash);
                 let mut out = Vec::with_capacity(128);
                 b.iter(|| {
                     out.clear();
@@ -157,17 +168,14 @@ fn tree_length(c: &mut Criterion) {
         };
     }
 
-    c.bench_functions(
-        "tree length",
-        vec![
-            Fun::new("length 2", length_n!(2)),
-            Fun::new("length 4", length_n!(4)),
-            Fun::new("length 8", length_n!(8)),
-            Fun::new("length 16", length_n!(16)),
-            Fun::new("length 32", length_n!(32)),
-        ],
-        stats(),
-    );
+    let mut group = c.benchmark_group("tree length");
+    let stats = stats();
+    group.bench_with_input(BenchmarkId::new("length", 2), &stats, length_n!(2));
+    group.bench_with_input(BenchmarkId::new("length", 4), &stats, length_n!(4));
+    group.bench_with_input(BenchmarkId::new("length", 8), &stats, length_n!(8));
+    group.bench_with_input(BenchmarkId::new("length", 16), &stats, length_n!(16));
+    group.bench_with_input(BenchmarkId::new("length", 32), &stats, length_n!(32));
+    group.finish();
 }
 
 fn interpret_style(c: &mut Criterion) {
@@ -182,7 +190,7 @@ fn interpret_style(c: &mut Criterion) {
                     style: $style,
                     sub: $content,
                 }]);
-                let mut i = Interpreter::new(s.clone(), true, true);
+                let mut i = Interpreter::new(s.clone(), true, Escapes::Bash);
                 let mut out = Vec::with_capacity(128);
                 b.iter(|| {
                     out.clear();
@@ -200,31 +208,36 @@ fn interpret_style(c: &mut Criterion) {
         ss.iter().collect()
     }
 
-    c.bench_functions(
-        "Interpreting Style",
-        vec![
-            Fun::new("Empty style", style!(Default::default(), Tree::new())),
-            Fun::new("Default style", style!(Default::default(), test())),
-            Fun::new("Bold", style!(make_style(&[Bold]), test())),
-            Fun::new(
-                "Bold, Underline text",
-                style!(make_style(&[Bold, Underline]), test()),
-            ),
-            Fun::new(
-                "Bold, Underline, Italic text",
-                style!(make_style(&[Bold, Underline, Italic]), test()),
-            ),
-            Fun::new(
-                "Colored Text",
-                style!(make_style(&[Fg(Red), Bg(White)]), test()),
-            ),
-            Fun::new(
-                "Colored Underline Text",
-                style!(make_style(&[Fg(Red), Bg(White), Underline]), test()),
-            ),
-        ],
-        stats(),
+    let mut group = c.benchmark_group("Interpreting Style");
+    let stats = stats();
+    group.bench_with_input(
+        "Empty style",
+        &stats,
+        style!(Default::default(), Tree::new()),
+    );
+    group.bench_with_input("Default style", &stats, style!(Default::default(), test()));
+    group.bench_with_input("Bold", &stats, style!(make_style(&[Bold]), test()));
+    group.bench_with_input(
+        "Bold, Underline text",
+        &stats,
+        style!(make_style(&[Bold, Underline]), test()),
+    );
+    group.bench_with_input(
+        "Bold, Underline, Italic text",
+        &stats,
+        style!(make_style(&[Bold, Underline, Italic]), test()),
+    );
+    group.bench_with_input(
+        "Colored Text",
+        &stats,
+        style!(make_style(&[Fg(Red), Bg(White)]), test()),
+    );
+    group.bench_with_input(
+        "Colored Underline Text",
+        &stats,
+        style!(make_style(&[Fg(Red), Bg(White), Underline]), test()),
     );
+    group.finish();
 }
 
 criterion_group!(
@@ -84,7 +84,7 @@ fn parse_group(c: &mut Criterion) {
     group.bench_with_input(
         BenchmarkId::new("isolated sub-parser", input.clone()),
         &input.as_ref(),
-        |b, i| b.iter(|| group_expression(i)),
+        |b, i| b.iter(|| group_expression(&parser::Definitions::default(), i)),
     );
 
     group.finish();
@@ -159,7 +159,7 @@ fn parse_named(c: &mut Criterion) {
     group.bench_with_input(
         BenchmarkId::new("isolated sub-parser", input.clone()),
         &input.as_ref(),
-        |b, i| b.iter(|| named_expression(i)),
+        |b, i| b.iter(|| named_expression(&parser::Definitions::default(), i)),
     );
 
     group.finish();
@@ -235,6 +235,7 @@ fn real_world(c: &mut Criterion) {
                 bold: false,
                 italics: false,
                 underline: false,
+                ..Default::default()
             },
             sub: Tree(vec![
                 Expression::Format {
@@ -244,6 +245,7 @@ fn real_world(c: &mut Criterion) {
                         bold: true,
                         italics: false,
                         underline: false,
+                        ..Default::default()
                     },
                     sub: Tree(vec![Expression::Literal("~".to_owned())]),
                 },
@@ -261,6 +263,7 @@ fn real_world(c: &mut Criterion) {
                         bold: true,
                         italics: false,
                         underline: false,
+                        ..Default::default()
                     },
                     sub: Tree(vec![Expression::Named {
                         name: Branch,
@@ -275,6 +278,7 @@ fn real_world(c: &mut Criterion) {
                         bold: false,
                         italics: false,
                         underline: false,
+                        ..Default::default()
                     },
                     sub: Tree(vec![Expression::Named {
                         name: Remote,
@@ -294,6 +298,7 @@ fn real_world(c: &mut Criterion) {
                                     bold: false,
                                     italics: false,
                                     underline: false,
+                                    ..Default::default()
                                 },
                                 sub: Tree(vec![Expression::Literal("↑".to_owned())]),
                             }]),
@@ -308,6 +313,7 @@ fn real_world(c: &mut Criterion) {
                                     bold: false,
                                     italics: false,
                                     underline: false,
+                                    ..Default::default()
                                 },
                                 sub: Tree(vec![Expression::Literal("↓".to_owned())]),
                             }]),
@@ -324,6 +330,7 @@ fn real_world(c: &mut Criterion) {
                         bold: false,
                         italics: false,
                         underline: false,
+                        ..Default::default()
                     },
                     sub: Tree(vec![
                         Expression::Format {
@@ -333,6 +340,7 @@ fn real_world(c: &mut Criterion) {
                                 bold: false,
                                 italics: false,
                                 underline: false,
+                                ..Default::default()
                             },
                             sub: Tree(vec![
                                 Expression::Named {
@@ -361,6 +369,7 @@ fn real_world(c: &mut Criterion) {
                                 bold: false,
                                 italics: false,
                                 underline: false,
+                                ..Default::default()
                             },
                             sub: Tree(vec![
                                 Expression::Named {
@@ -391,6 +400,7 @@ fn real_world(c: &mut Criterion) {
                                     bold: false,
                                     italics: false,
                                     underline: false,
+                                    ..Default::default()
                                 },
                                 sub: Tree(vec![Expression::Literal("@".to_owned())]),
                             }]),
@@ -2,8 +2,10 @@
 use proptest::collection::vec;
 #[cfg(test)]
 use proptest::prelude::*;
+use regex::Regex;
 use std::fmt;
 use std::iter::{Extend, FromIterator, IntoIterator};
+use std::sync::Arc;
 
 /// All valid expression names
 ///
@@ -23,9 +25,31 @@ pub enum Name {
     DeletedStaged,
     Renamed,
     Stashed,
+    Operation,
+    RebaseStep,
+    RebaseTotal,
+    RebaseOnto,
+    Insertions,
+    InsertionsStaged,
+    Deletions,
+    DeletionsStaged,
+    Incomplete,
     Quote,
 }
 
+impl Name {
+    /// Whether the name represents a number, which can be compared
+    pub fn is_numeric(self) -> bool {
+        use Name::*;
+        match self {
+            Ahead | Behind | Conflict | Added | Untracked | Modified | Unstaged | Deleted
+            | DeletedStaged | Renamed | Stashed | RebaseStep | RebaseTotal | Insertions
+            | InsertionsStaged | Deletions | DeletionsStaged => true,
+            Branch | Remote | Operation | RebaseOnto | Incomplete | Quote => false,
+        }
+    }
+}
+
 impl fmt::Display for Name {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let literal = match self {
@@ -42,6 +66,15 @@ impl fmt::Display for Name {
             Name::Deleted => "d",
             Name::DeletedStaged => "D",
             Name::Renamed => "R",
+            Name::Operation => "o",
+            Name::RebaseStep => "n",
+            Name::RebaseTotal => "N",
+            Name::RebaseOnto => "O",
+            Name::Insertions => "i",
+            Name::InsertionsStaged => "I",
+            Name::Deletions => "x",
+            Name::DeletionsStaged => "X",
+            Name::Incomplete => "!",
             Name::Quote => "\\\'",
         };
         write!(f, "{}", literal)
@@ -66,10 +99,67 @@ pub fn arb_name() -> impl Strategy<Value = Name> {
         Just(DeletedStaged),
         Just(Renamed),
         Just(Stashed),
+        Just(Operation),
+        Just(RebaseStep),
+        Just(RebaseTotal),
+        Just(RebaseOnto),
+        Just(Insertions),
+        Just(InsertionsStaged),
+        Just(Deletions),
+        Just(DeletionsStaged),
+        Just(Incomplete),
         Just(Quote),
     ]
 }
 
+#[cfg(test)]
+pub fn arb_numeric_name() -> impl Strategy<Value = Name> {
+    arb_name().prop_filter("Only numeric names can be compared", |n| n.is_numeric())
+}
+
+/// Comparison between the value of a numeric name and a constant
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Comparison {
+    /// `>`, greater than
+    Greater,
+    /// `<`, less than
+    Less,
+    /// `==`, equal to
+    Equal,
+}
+
+impl Comparison {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Comparison::Greater => ">",
+            Comparison::Less => "<",
+            Comparison::Equal => "==",
+        }
+    }
+
+    /// Compare a value to a constant
+    pub fn compare(&self, value: u32, constant: u32) -> bool {
+        match self {
+            Comparison::Greater => value > constant,
+            Comparison::Less => value < constant,
+            Comparison::Equal => value == constant,
+        }
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[cfg(test)]
+pub fn arb_comparison() -> impl Strategy<Value = Comparison> {
+    use self::Comparison::*;
+
+    prop_oneof![Just(Greater), Just(Less), Just(Equal)]
+}
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum Color {
     /// Make text red
@@ -86,12 +176,48 @@ pub enum Color {
     Cyan,
     /// Make the text white
     White,
-    /// Make the text bright black
+    /// Make the text black
     Black,
+    /// Make the text bright red
+    BrightRed,
+    /// Make the text bright green
+    BrightGreen,
+    /// Make the text bright yellow
+    BrightYellow,
+    /// Make the text bright blue
+    BrightBlue,
+    /// Make the text bright purple
+    BrightMagenta,
+    /// Make the text bright cyan
+    BrightCyan,
+    /// Make the text bright white
+    BrightWhite,
+    /// Make the text bright black, or gray
+    BrightBlack,
     /// Provide a 256 color table text color value
+    Indexed(u8),
+    /// Provide a 24 bit RGB text color value
     RGB(u8, u8, u8),
 }
 
+impl Color {
+    /// The bright variant of one of the eight basic colors, any other color is unchanged
+    pub fn bright(self) -> Color {
+        use Color::*;
+        match self {
+            Red => BrightRed,
+            Green => BrightGreen,
+            Yellow => BrightYellow,
+            Blue => BrightBlue,
+            Magenta => BrightMagenta,
+            Cyan => BrightCyan,
+            White => BrightWhite,
+            Black => BrightBlack,
+            other => other,
+        }
+    }
+}
+
 /// All valid style markers
 ///
 /// Defines the range of possible styles
@@ -105,6 +231,18 @@ pub enum Style {
     Underline,
     /// Italisize text in the terminal; ANSI code 03 equivalent
     Italic,
+    /// Dim or faint text in the terminal; ANSI code 02 equivalent
+    Dim,
+    /// Blinking text in the terminal; ANSI code 05 equivalent
+    Blink,
+    /// Swap the foreground and background colors; ANSI code 07 equivalent
+    Reverse,
+    /// Cross out text in the terminal; ANSI code 09 equivalent
+    Strikethrough,
+    /// Underline text twice in the terminal; ANSI code 21 equivalent
+    DoubleUnderline,
+    /// Draw a line over text in the terminal; ANSI code 53 equivalent
+    Overline,
     /// Set a foreground color
     Fg(Color),
     /// Set a background color
@@ -119,6 +257,12 @@ impl fmt::Display for Style {
             Style::Bold => write!(f, "*")?,
             Style::Underline => write!(f, "_")?,
             Style::Italic => write!(f, "i")?,
+            Style::Dim => write!(f, "d")?,
+            Style::Blink => write!(f, "f")?,
+            Style::Reverse => write!(f, "v")?,
+            Style::Strikethrough => write!(f, "x")?,
+            Style::DoubleUnderline => write!(f, "=")?,
+            Style::Overline => write!(f, "o")?,
             Style::Fg(Red) => write!(f, "r")?,
             Style::Bg(Red) => write!(f, "R")?,
             Style::Fg(Green) => write!(f, "g")?,
@@ -135,6 +279,24 @@ impl fmt::Display for Style {
             Style::Bg(White) => write!(f, "W")?,
             Style::Fg(Black) => write!(f, "k")?,
             Style::Bg(Black) => write!(f, "K")?,
+            Style::Fg(BrightRed) => write!(f, "+r")?,
+            Style::Bg(BrightRed) => write!(f, "+R")?,
+            Style::Fg(BrightGreen) => write!(f, "+g")?,
+            Style::Bg(BrightGreen) => write!(f, "+G")?,
+            Style::Fg(BrightYellow) => write!(f, "+y")?,
+            Style::Bg(BrightYellow) => write!(f, "+Y")?,
+            Style::Fg(BrightBlue) => write!(f, "+b")?,
+            Style::Bg(BrightBlue) => write!(f, "+B")?,
+            Style::Fg(BrightMagenta) => write!(f, "+m")?,
+            Style::Bg(BrightMagenta) => write!(f, "+M")?,
+            Style::Fg(BrightCyan) => write!(f, "+c")?,
+            Style::Bg(BrightCyan) => write!(f, "+C")?,
+            Style::Fg(BrightWhite) => write!(f, "+w")?,
+            Style::Bg(BrightWhite) => write!(f, "+W")?,
+            Style::Fg(BrightBlack) => write!(f, "+k")?,
+            Style::Bg(BrightBlack) => write!(f, "+K")?,
+            &Style::Fg(Indexed(i)) => write!(f, "({})", i)?,
+            &Style::Bg(Indexed(i)) => write!(f, "<{}>", i)?,
             &Style::Fg(RGB(r, g, b)) => write!(f, "[{},{},{}]", r, g, b)?,
             &Style::Bg(RGB(r, g, b)) => write!(f, "{{{},{},{}}}", r, g, b)?,
         };
@@ -152,6 +314,12 @@ pub fn arb_style() -> impl Strategy<Value = Style> {
         Just(Bold),
         Just(Underline),
         Just(Italic),
+        Just(Dim),
+        Just(Blink),
+        Just(Reverse),
+        Just(Strikethrough),
+        Just(DoubleUnderline),
+        Just(Overline),
         Just(Fg(Red)),
         Just(Bg(Red)),
         Just(Fg(Green)),
@@ -168,11 +336,31 @@ pub fn arb_style() -> impl Strategy<Value = Style> {
         Just(Bg(White)),
         Just(Fg(Black)),
         Just(Bg(Black)),
+        arb_bright_color().prop_map(Fg),
+        arb_bright_color().prop_map(Bg),
+        any::<u8>().prop_map(|i| Fg(Indexed(i))),
+        any::<u8>().prop_map(|i| Bg(Indexed(i))),
         any::<(u8, u8, u8)>().prop_map(|(r, g, b)| Fg(RGB(r, g, b))),
         any::<(u8, u8, u8)>().prop_map(|(r, g, b)| Bg(RGB(r, g, b))),
     ]
 }
 
+#[cfg(test)]
+fn arb_bright_color() -> impl Strategy<Value = Color> {
+    use self::Color::*;
+
+    prop_oneof![
+        Just(BrightRed),
+        Just(BrightGreen),
+        Just(BrightYellow),
+        Just(BrightBlue),
+        Just(BrightMagenta),
+        Just(BrightCyan),
+        Just(BrightWhite),
+        Just(BrightBlack),
+    ]
+}
+
 /// An aggregate unit which describes the sub total of a set of styles
 ///
 /// ```
@@ -204,13 +392,19 @@ pub fn arb_style() -> impl Strategy<Value = Style> {
 /// let green_repeat: CompleteStyle = std::iter::repeat(&Fg(Green)).take(10).collect();
 /// assert_eq!(green, green_repeat);
 /// ```
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
 pub struct CompleteStyle {
     pub fg: Option<Color>,
     pub bg: Option<Color>,
     pub bold: bool,
     pub italics: bool,
     pub underline: bool,
+    pub dim: bool,
+    pub blink: bool,
+    pub reverse: bool,
+    pub strikethrough: bool,
+    pub double_underline: bool,
+    pub overline: bool,
 }
 
 impl CompleteStyle {
@@ -222,23 +416,17 @@ impl CompleteStyle {
             Bold => self.bold = true,
             Italic => self.italics = true,
             Underline => self.underline = true,
+            Dim => self.dim = true,
+            Blink => self.blink = true,
+            Reverse => self.reverse = true,
+            Strikethrough => self.strikethrough = true,
+            DoubleUnderline => self.double_underline = true,
+            Overline => self.overline = true,
             Reset => *self = Default::default(),
         }
     }
 }
 
-impl Default for CompleteStyle {
-    fn default() -> Self {
-        CompleteStyle {
-            fg: None,
-            bg: None,
-            bold: false,
-            italics: false,
-            underline: false,
-        }
-    }
-}
-
 impl std::ops::AddAssign for CompleteStyle {
     fn add_assign(&mut self, with: Self) {
         if with == Default::default() {
@@ -251,6 +439,12 @@ impl std::ops::AddAssign for CompleteStyle {
             bold: with.bold || self.bold,
             italics: with.italics || self.italics,
             underline: with.underline || self.underline,
+            dim: with.dim || self.dim,
+            blink: with.blink || self.blink,
+            reverse: with.reverse || self.reverse,
+            strikethrough: with.strikethrough || self.strikethrough,
+            double_underline: with.double_underline || self.double_underline,
+            overline: with.overline || self.overline,
         }
     }
 }
@@ -286,6 +480,24 @@ impl fmt::Display for CompleteStyle {
         if self.underline {
             write!(f, "{}", Underline)?;
         }
+        if self.dim {
+            write!(f, "{}", Dim)?;
+        }
+        if self.blink {
+            write!(f, "{}", Blink)?;
+        }
+        if self.reverse {
+            write!(f, "{}", Reverse)?;
+        }
+        if self.strikethrough {
+            write!(f, "{}", Strikethrough)?;
+        }
+        if self.double_underline {
+            write!(f, "{}", DoubleUnderline)?;
+        }
+        if self.overline {
+            write!(f, "{}", Overline)?;
+        }
 
         Ok(())
     }
@@ -405,6 +617,169 @@ pub fn arb_separator() -> impl Strategy<Value = Separator> {
     ]
 }
 
+/// Text written as a string between single quotes, or between double quotes with escapes when
+/// it contains a single quote
+struct Quoted<'a>(&'a str);
+
+impl<'a> fmt::Display for Quoted<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.0.contains('\'') {
+            return write!(f, "'{}'", self.0);
+        }
+
+        write!(f, "\"")?;
+        for c in self.0.chars() {
+            match c {
+                '"' | '\\' => write!(f, "\\{}", c)?,
+                c => write!(f, "{}", c)?,
+            }
+        }
+        write!(f, "\"")
+    }
+}
+
+/// Transformation of the text output by a tree
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Filter {
+    /// Limit the text to a number of terminal columns, ending truncated text with an ellipsis
+    Truncate { width: u32, ellipsis: String },
+    /// Pad the text with spaces to at least a number of terminal columns
+    Pad { align: Align, width: u32 },
+    /// Remove a prefix from the text
+    StripPrefix(String),
+    /// Replace the text with the first capture group of a pattern, or the whole match when the
+    /// pattern has no groups
+    Capture(Pattern),
+    /// Replace every match of a pattern, where `$1` or `$name` refer to capture groups
+    Replace {
+        pattern: Pattern,
+        replacement: String,
+    },
+    /// Make every letter uppercase
+    Upper,
+    /// Make every letter lowercase
+    Lower,
+    /// Make the first letter of every word uppercase, and the rest lowercase
+    Title,
+    /// Keep only a number of characters from the start of the text
+    First(u32),
+}
+
+/// A regular expression, which is equal to other expressions with the same pattern
+#[derive(Debug, Clone)]
+pub struct Pattern(pub Regex);
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for Pattern {}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.as_str())
+    }
+}
+
+/// Where padded text is placed in its columns
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Align {
+    /// Pad the text on the right
+    Left,
+    /// Pad the text on the left
+    Right,
+    /// Pad the text on both sides, with the extra space on the right
+    Center,
+}
+
+impl Align {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Align::Left => "<",
+            Align::Right => ">",
+            Align::Center => "^",
+        }
+    }
+}
+
+impl fmt::Display for Align {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl Filter {
+    /// Ellipsis used by truncation when none is given
+    pub const ELLIPSIS: &'static str = "…";
+
+    /// Largest number of columns or characters which a filter accepts
+    pub const MAX_WIDTH: u32 = 4096;
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Filter::Truncate { width, ellipsis } => {
+                write!(f, ".{}", width)?;
+                if ellipsis != Filter::ELLIPSIS {
+                    write!(f, "{}", Quoted(ellipsis))?;
+                }
+                Ok(())
+            }
+            Filter::Pad { align, width } => write!(f, "{}{}", align, width),
+            Filter::StripPrefix(prefix) => write!(f, "#{}", Quoted(prefix)),
+            Filter::Capture(pattern) => write!(f, "/{}", Quoted(pattern.0.as_str())),
+            Filter::Replace {
+                pattern,
+                replacement,
+            } => write!(f, "/{}/{}", Quoted(pattern.0.as_str()), Quoted(replacement)),
+            Filter::Upper => write!(f, "U"),
+            Filter::Lower => write!(f, "L"),
+            Filter::Title => write!(f, "T"),
+            Filter::First(count) => write!(f, ":{}", count),
+        }
+    }
+}
+
+#[cfg(test)]
+pub fn arb_filter() -> impl Strategy<Value = Filter> {
+    use self::Align::*;
+
+    prop_oneof![
+        (0..=Filter::MAX_WIDTH, any::<String>())
+            .prop_map(|(width, ellipsis)| Filter::Truncate { width, ellipsis }),
+        (
+            prop_oneof![Just(Left), Just(Right), Just(Center)],
+            0..=Filter::MAX_WIDTH
+        )
+            .prop_map(|(align, width)| Filter::Pad { align, width }),
+        any::<String>().prop_map(Filter::StripPrefix),
+        arb_pattern().prop_map(Filter::Capture),
+        (arb_pattern(), any::<String>()).prop_map(|(pattern, replacement)| Filter::Replace {
+            pattern,
+            replacement,
+        }),
+        Just(Filter::Upper),
+        Just(Filter::Lower),
+        Just(Filter::Title),
+        (0..=Filter::MAX_WIDTH).prop_map(Filter::First),
+    ]
+}
+
+#[cfg(test)]
+fn arb_pattern() -> impl Strategy<Value = Pattern> {
+    prop_oneof![
+        "[a-z0-9/]{0,8}",
+        Just(r"[A-Z]+-[0-9]+".to_owned()),
+        Just(r"^users/[^/]+/(.*)$".to_owned()),
+        Just(r"(?P<ticket>[A-Z]+-\d+)-.*".to_owned()),
+        Just(r"'\\'".to_owned()),
+    ]
+    .prop_map(|pattern| Pattern(Regex::new(&pattern).unwrap()))
+}
+
 /// The types of possible expressions which form an expression tree
 ///
 /// The gist format has three types of valid expressions:
@@ -431,12 +806,45 @@ pub fn arb_separator() -> impl Strategy<Value = Separator> {
 ///
 /// By nesting groups of expressions, we can create an implicit tree.
 ///
-/// A **literal expression** is any valid utf8 characters between single quites, except for single
-/// quotes and backslashes.
+/// A **literal expression** is any valid utf8 characters between single quotes, except for single
+/// quotes, or between double quotes, where strings may contain the escapes `\n`, `\t`, `\u{...}`,
+/// `\"`, `\'` and `\\`.
 ///
 /// ```txt
-/// 'hello''we''are''literal''expressions''I am one including whitespace'
+/// 'hello''we''are''literal''expressions''I am one including whitespace'"it's""line\n"
 /// ```
+///
+/// A **comparison** filters a numeric named expression, which is only output when its value
+/// satisfies the comparison; this makes comparisons useful as conditions:
+///
+/// - `->10` outputs the number of commits behind only when it is greater than 10
+/// - `?(+==0, 'up to date')` outputs `up to date` when there are no commits ahead
+///
+/// A **conditional expression** chooses between two trees depending on whether its condition,
+/// which is not output, is empty.  Its arguments are separated by commas, so they may not contain
+/// comma separators outside of nested expressions:
+///
+/// - `?(condition, then)` outputs `then` when `condition` is not empty
+/// - `?(condition, then, otherwise)` outputs `otherwise` when `condition` is empty
+///
+/// A **definition** binds a name made of letters, digits, and underscores to a tree, without
+/// outputting anything; a **reference** later in the format outputs the tree bound to the name:
+///
+/// - `&staged=(#g(MARD))` defines `staged`
+/// - `&staged` outputs the tree bound to `staged`
+///
+/// A **filter expression** transforms the text output by its sub-tree, and is written as `%`
+/// followed by the filter and the sub-tree:
+///
+/// - `%.20(b)` limits the branch name to 20 columns, ending it with `…` when it is truncated
+/// - `%.20'..'(b)` ends the truncated branch name with `..` instead
+/// - `%<20(b)`, `%>20(b)` and `%^20(b)` pad the branch name with spaces to 20 columns, placing
+///   it on the left, right, or center
+/// - `%#'users/'(b)` removes `users/` from the start of the branch name
+/// - `%/'[A-Z]+-[0-9]+'(b)` outputs the part of the branch name which matches a pattern
+/// - `%/'^users/[^/]+/'/''(b)` replaces the matches of a pattern in the branch name
+/// - `%U(o)`, `%L(o)` and `%T(o)` make the operation uppercase, lowercase, or title case
+/// - `%:1(B)` outputs the first character of the remote name
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Expression {
     /// An expression with a name and optional arguments which represents git repository stats
@@ -446,6 +854,17 @@ pub enum Expression {
         /// Arguments to the expression, zero or more
         sub: Tree,
     },
+    /// A named expression which is only output when its value satisfies a comparison
+    Compare {
+        /// Name of the expression, which must be numeric
+        name: Name,
+        /// How the value of the name is compared to the constant
+        op: Comparison,
+        /// Constant the value of the name is compared to
+        value: u32,
+        /// Arguments to the expression, zero or more
+        sub: Tree,
+    },
     /// An expression which represents terminal text formatting
     Format { style: CompleteStyle, sub: Tree },
     /// A group of sub-expressions which forms an expression tree
@@ -459,6 +878,36 @@ pub enum Expression {
     Literal(String),
     /// Separator between elements in a tree
     Separator(Separator),
+    /// An expression which chooses between two trees depending on whether a condition is empty
+    Conditional {
+        /// Tree which is interpreted, but not output, to choose a branch
+        condition: Tree,
+        /// Tree output when the condition is not empty
+        then: Tree,
+        /// Tree output when the condition is empty
+        otherwise: Tree,
+    },
+    /// A name bound to a tree, which is output by references to the name
+    Definition {
+        /// Name the tree is bound to
+        name: String,
+        /// Tree bound to the name
+        sub: Arc<Tree>,
+    },
+    /// A reference to the tree bound to a name by an earlier definition
+    Reference {
+        /// Name the tree is bound to
+        name: String,
+        /// Tree bound to the name, resolved by the parser and shared with the definition
+        sub: Arc<Tree>,
+    },
+    /// An expression which transforms the text output by a tree
+    Filter {
+        /// How the text is transformed
+        filter: Filter,
+        /// Tree whose output is transformed
+        sub: Tree,
+    },
 }
 
 impl fmt::Display for Expression {
@@ -472,6 +921,19 @@ impl fmt::Display for Expression {
                     write!(f, "({})", sub)
                 }
             }
+            Expression::Compare {
+                ref name,
+                ref op,
+                ref value,
+                ref sub,
+            } => {
+                write!(f, "{}{}{}", name, op, value)?;
+                if sub.0.is_empty() {
+                    Ok(())
+                } else {
+                    write!(f, "({})", sub)
+                }
+            }
             Expression::Group { ref d, ref sub } => match d {
                 Delimiter::Square => write!(f, "[{}]", sub),
                 Delimiter::Angle => write!(f, "<{}>", sub),
@@ -483,8 +945,25 @@ impl fmt::Display for Expression {
                 write!(f, "{}", style)?;
                 write!(f, "({})", sub)
             }
-            Expression::Literal(ref string) => write!(f, "'{}'", string),
+            Expression::Literal(ref string) => write!(f, "{}", Quoted(string)),
             Expression::Separator(s) => write!(f, "{}", s),
+            Expression::Conditional {
+                ref condition,
+                ref then,
+                ref otherwise,
+            } => {
+                write!(f, "?({},{}", condition, then)?;
+                if !otherwise.0.is_empty() {
+                    write!(f, ",{}", otherwise)?;
+                }
+                write!(f, ")")
+            }
+            Expression::Definition { ref name, ref sub } => write!(f, "&{}=({})", name, sub),
+            Expression::Reference { ref name, .. } => write!(f, "&{}", name),
+            Expression::Filter {
+                ref filter,
+                ref sub,
+            } => write!(f, "%{}({})", filter, sub),
         }
     }
 }
@@ -495,29 +974,46 @@ pub fn arb_expression() -> impl Strategy<Value = Expression> {
 
     let leaf = prop_oneof![
         arb_name().prop_map(|name| Named {
-            name: name,
+            name,
             sub: Tree::new(),
         }),
         vec(arb_style(), 1..5).prop_map(|style| Format {
             style: style.iter().collect(),
             sub: Tree::new(),
         }),
-        "[^']*".prop_map(Literal),
+        (arb_numeric_name(), arb_comparison(), any::<u32>()).prop_map(|(name, op, value)| {
+            Compare {
+                name,
+                op,
+                value,
+                sub: Tree::new(),
+            }
+        }),
+        any::<String>().prop_map(Literal),
         arb_separator().prop_map(Separator),
     ];
 
     leaf.prop_recursive(8, 64, 10, |inner| {
         prop_oneof![
-            (arb_name(), vec(inner.clone(), 0..10)).prop_map(|(name, sub)| Named {
-                name: name,
-                sub: Tree(sub),
-            }),
-            (vec(arb_style(), 1..10), vec(inner.clone(), 0..10)).prop_map(|(style, sub)| Format {
+            (arb_name(), arb_sub(inner.clone())).prop_map(|(name, sub)| Named { name, sub }),
+            (vec(arb_style(), 1..10), arb_sub(inner.clone())).prop_map(|(style, sub)| Format {
                 style: style.iter().collect(),
-                sub: Tree(sub),
+                sub,
             }),
-            (arb_delimiter(), vec(inner.clone(), 0..10)).prop_map(|(delimiter, sub)| Group {
-                d: delimiter,
+            (arb_delimiter(), arb_sub(inner.clone()))
+                .prop_map(|(delimiter, sub)| Group { d: delimiter, sub }),
+            (
+                vec(inner.clone(), 0..5),
+                vec(inner.clone(), 0..5),
+                vec(inner.clone(), 0..5)
+            )
+                .prop_map(|(condition, then, otherwise)| Conditional {
+                    condition: without_commas(condition),
+                    then: without_commas(then),
+                    otherwise: without_commas(otherwise),
+                }),
+            (arb_filter(), vec(inner.clone(), 0..10)).prop_map(|(filter, sub)| Filter {
+                filter,
                 sub: Tree(sub),
             }),
             arb_separator().prop_map(Separator),
@@ -525,11 +1021,46 @@ pub fn arb_expression() -> impl Strategy<Value = Expression> {
     })
 }
 
+/// Expressions of a sub-tree, which may define a name and then refer to it
+///
+/// A reference must follow its definition, and it is followed by any other expressions, which
+/// may start with characters that could continue its name.
+#[cfg(test)]
+fn arb_sub(inner: BoxedStrategy<Expression>) -> impl Strategy<Value = Tree> {
+    (
+        vec(inner.clone(), 0..5),
+        proptest::option::of(("[a-z][a-z0-9_]{0,8}", vec(inner.clone(), 0..5))),
+        vec(inner, 0..5),
+    )
+        .prop_map(|(mut exps, definition, rest)| {
+            if let Some((name, sub)) = definition {
+                let sub = Arc::new(Tree(sub));
+                exps.push(Expression::Definition {
+                    name: name.clone(),
+                    sub: Arc::clone(&sub),
+                });
+                exps.push(Expression::Reference { name, sub });
+            }
+            exps.extend(rest);
+            Tree(exps)
+        })
+}
+
+/// Conditional arguments are separated by commas, so they cannot contain comma separators
+#[cfg(test)]
+fn without_commas(exps: Vec<Expression>) -> Tree {
+    Tree(
+        exps.into_iter()
+            .filter(|e| *e != Expression::Separator(self::Separator::Comma))
+            .collect(),
+    )
+}
+
 /// A collection of expressions which may recursively form an expression tree
 ///
 /// Seperate struct, use mutual recursion between tree and expressions to make parsing easier to
 /// implement.  May combine them in the future.
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
 pub struct Tree(pub Vec<Expression>);
 
 impl Tree {
@@ -537,18 +1068,51 @@ impl Tree {
     pub fn new() -> Tree {
         Tree(Vec::new())
     }
-}
 
-impl Default for Tree {
-    fn default() -> Self {
-        Tree(Vec::new())
+    /// Visit every name in the tree, including names in nested sub-trees
+    ///
+    /// Names in a definition are visited once where the tree is defined, rather than at every
+    /// reference to it, even if the definition is never referenced.
+    pub fn for_each_name<F: FnMut(Name)>(&self, f: &mut F) {
+        use Expression::*;
+        for exp in &self.0 {
+            match exp {
+                Named { name, sub } | Compare { name, sub, .. } => {
+                    f(*name);
+                    sub.for_each_name(f);
+                }
+                Format { sub, .. } | Group { sub, .. } | Filter { sub, .. } => sub.for_each_name(f),
+                Definition { sub, .. } => sub.for_each_name(f),
+                Conditional {
+                    condition,
+                    then,
+                    otherwise,
+                } => {
+                    condition.for_each_name(f);
+                    then.for_each_name(f);
+                    otherwise.for_each_name(f);
+                }
+                Literal(_) | Separator(_) | Reference { .. } => {}
+            }
+        }
     }
 }
 
 impl fmt::Display for Tree {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for exp in &self.0 {
-            write!(f, "{}", exp)?;
+        let mut exps = self.0.iter().peekable();
+        while let Some(exp) = exps.next() {
+            match (exp, exps.peek()) {
+                // Without braces, the name would run into the expression which follows it
+                (Expression::Reference { ref name, .. }, Some(next))
+                    if next
+                        .to_string()
+                        .starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_') =>
+                {
+                    write!(f, "&{{{}}}", name)?
+                }
+                _ => write!(f, "{}", exp)?,
+            }
         }
         Ok(())
     }
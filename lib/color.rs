@@ -1,6 +1,11 @@
 use crate::ast::{Color, CompleteStyle};
 
+use serde::Deserialize;
+
+use std::borrow::Cow;
+use std::fmt;
 use std::io;
+use std::str::FromStr;
 
 macro_rules! e {
     ($c:tt, $($cn:expr),*) => {
@@ -14,46 +19,443 @@ macro_rules! e {
     };
 }
 
+/// How escape sequences are marked as non-printing for the prompt of a shell
+///
+/// Without the markers, the shell counts escape sequences towards the width of the prompt, which
+/// breaks line wrapping.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Escapes {
+    /// Leave escape sequences unmarked
+    #[default]
+    None,
+    /// Wrap escape sequences in `\x01` and `\x02`, and escape `\`, `$` and `` ` `` in text from
+    /// the repository
+    Bash,
+    /// Wrap escape sequences in `%{` and `%}`, and escape `%` in text from the repository
+    Zsh,
+    /// Wrap escape sequences in `%{` and `%}`, and escape `%` and `!` in text from the repository
+    Tcsh,
+}
+
+impl Escapes {
+    /// Names of every escape mode, as accepted by `FromStr`
+    pub const NAMES: [&'static str; 4] = ["bash", "zsh", "tcsh", "none"];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Escapes::None => "none",
+            Escapes::Bash => "bash",
+            Escapes::Zsh => "zsh",
+            Escapes::Tcsh => "tcsh",
+        }
+    }
+
+    /// Marks the start of a non-printing sequence
+    pub(crate) fn start(&self) -> &'static str {
+        match self {
+            Escapes::None => "",
+            Escapes::Bash => "\u{01}",
+            Escapes::Zsh | Escapes::Tcsh => "%{",
+        }
+    }
+
+    /// Marks the end of a non-printing sequence
+    pub(crate) fn end(&self) -> &'static str {
+        match self {
+            Escapes::None => "",
+            Escapes::Bash => "\u{02}",
+            Escapes::Zsh | Escapes::Tcsh => "%}",
+        }
+    }
+
+    /// Escape sequence which resets all styles, marked as non-printing
+    pub(crate) fn reset(&self) -> &'static str {
+        match self {
+            Escapes::None => e!(),
+            Escapes::Bash => concat!["\u{01}", e!(), "\u{02}"],
+            Escapes::Zsh | Escapes::Tcsh => concat!["%{", e!(), "%}"],
+        }
+    }
+
+    /// Escape text from the repository, like branch names, which the shell would otherwise expand
+    pub(crate) fn escape<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        match self {
+            // Bash decodes backslash escapes in the prompt, and then expands it like a string in
+            // double quotes, so each character has to survive both steps
+            Escapes::Bash if text.contains(['\\', '$', '`']) => {
+                let mut escaped = String::with_capacity(text.len() * 2);
+                for c in text.chars() {
+                    match c {
+                        '\\' => escaped.push_str(r"\\\\"),
+                        '$' | '`' => {
+                            escaped.push_str(r"\\");
+                            escaped.push(c);
+                        }
+                        c => escaped.push(c),
+                    }
+                }
+                escaped.into()
+            }
+            Escapes::Zsh if text.contains('%') => text.replace('%', "%%").into(),
+            Escapes::Tcsh if text.contains(['%', '!']) => {
+                text.replace('%', "%%").replace('!', "\\!").into()
+            }
+            _ => text.into(),
+        }
+    }
+}
+
+impl fmt::Display for Escapes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Escapes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Escapes::None),
+            "bash" => Ok(Escapes::Bash),
+            "zsh" => Ok(Escapes::Zsh),
+            "tcsh" => Ok(Escapes::Tcsh),
+            _ => Err(format!(
+                "unknown shell `{}`, expected one of {}",
+                s,
+                Escapes::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// When to use colors and styles in the output
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorChoice {
+    /// Use colors for terminals, unless the `NO_COLOR` environment variable is set
+    Auto,
+    /// Always use colors, the default since prompts capture the output rather than showing it
+    #[default]
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Names of every choice, as accepted by `FromStr`
+    pub const NAMES: [&'static str; 3] = ["auto", "always", "never"];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ColorChoice::Auto => "auto",
+            ColorChoice::Always => "always",
+            ColorChoice::Never => "never",
+        }
+    }
+
+    /// Whether to use colors for output which is shown by a terminal
+    pub fn use_color(&self, terminal: bool) -> bool {
+        // See https://no-color.org, an empty `NO_COLOR` does not disable colors
+        let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+        match self {
+            ColorChoice::Auto => terminal && !no_color,
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+impl fmt::Display for ColorChoice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(format!(
+                "unknown color choice `{}`, expected one of {}",
+                s,
+                ColorChoice::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// Colors which the terminal is able to show
+///
+/// RGB colors are approximated by the nearest color the terminal can show.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, Deserialize)]
+pub enum ColorDepth {
+    /// No colors at all, only text styles like bold
+    #[serde(rename = "none")]
+    None,
+    /// The 16 standard and bright colors
+    #[serde(rename = "16")]
+    Ansi16,
+    /// The 256 color xterm palette
+    #[serde(rename = "256")]
+    Ansi256,
+    /// 24 bit RGB colors
+    #[default]
+    #[serde(rename = "truecolor")]
+    TrueColor,
+}
+
+impl ColorDepth {
+    /// Names of every color depth, as accepted by `FromStr`
+    pub const NAMES: [&'static str; 4] = ["truecolor", "256", "16", "none"];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ColorDepth::None => "none",
+            ColorDepth::Ansi16 => "16",
+            ColorDepth::Ansi256 => "256",
+            ColorDepth::TrueColor => "truecolor",
+        }
+    }
+
+    /// Detect the color depth of the terminal from the `COLORTERM` and `TERM` variables
+    pub fn from_env() -> ColorDepth {
+        let colorterm = std::env::var("COLORTERM").unwrap_or_default();
+        let term = std::env::var("TERM").ok();
+        ColorDepth::detect(&colorterm, term.as_deref())
+    }
+
+    fn detect(colorterm: &str, term: Option<&str>) -> ColorDepth {
+        if colorterm == "truecolor" || colorterm == "24bit" {
+            return ColorDepth::TrueColor;
+        }
+
+        match term {
+            // Terminals which do not set TERM, like the Windows console, support RGB colors
+            None => ColorDepth::TrueColor,
+            Some("dumb") => ColorDepth::None,
+            Some(term) if term.contains("truecolor") || term.contains("direct") => {
+                ColorDepth::TrueColor
+            }
+            Some(term) if term.contains("256color") => ColorDepth::Ansi256,
+            Some(_) => ColorDepth::Ansi16,
+        }
+    }
+}
+
+impl fmt::Display for ColorDepth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for ColorDepth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "truecolor" | "24bit" => Ok(ColorDepth::TrueColor),
+            "256" => Ok(ColorDepth::Ansi256),
+            "16" => Ok(ColorDepth::Ansi16),
+            "none" => Ok(ColorDepth::None),
+            _ => Err(format!(
+                "unknown color depth `{}`, expected one of {}",
+                s,
+                ColorDepth::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// Write a color at the given color depth, `base` is 30 for foreground and 40 for background
+fn write_color<W: io::Write>(
+    w: &mut W,
+    color: Color,
+    base: u8,
+    depth: ColorDepth,
+) -> io::Result<()> {
+    use Color::*;
+
+    match color {
+        Black => write_ansi16(w, 0, base),
+        Red => write_ansi16(w, 1, base),
+        Green => write_ansi16(w, 2, base),
+        Yellow => write_ansi16(w, 3, base),
+        Blue => write_ansi16(w, 4, base),
+        Magenta => write_ansi16(w, 5, base),
+        Cyan => write_ansi16(w, 6, base),
+        White => write_ansi16(w, 7, base),
+        BrightBlack => write_ansi16(w, 8, base),
+        BrightRed => write_ansi16(w, 9, base),
+        BrightGreen => write_ansi16(w, 10, base),
+        BrightYellow => write_ansi16(w, 11, base),
+        BrightBlue => write_ansi16(w, 12, base),
+        BrightMagenta => write_ansi16(w, 13, base),
+        BrightCyan => write_ansi16(w, 14, base),
+        BrightWhite => write_ansi16(w, 15, base),
+        Indexed(i) => write_indexed(w, i, base, depth),
+        RGB(r, g, b) => write_rgb(w, (r, g, b), base, depth),
+    }
+}
+
+/// Write one of the 16 standard and bright colors, which every color terminal supports
+fn write_ansi16<W: io::Write>(w: &mut W, index: u8, base: u8) -> io::Result<()> {
+    match index {
+        0..=7 => write!(w, "\x1B[{}m", base + index),
+        _ => write!(w, "\x1B[{}m", base + 60 + index - 8),
+    }
+}
+
+/// Write a color of the 256 color table at the given color depth
+fn write_indexed<W: io::Write>(
+    w: &mut W,
+    index: u8,
+    base: u8,
+    depth: ColorDepth,
+) -> io::Result<()> {
+    match depth {
+        ColorDepth::TrueColor | ColorDepth::Ansi256 => {
+            write!(w, "\x1B[{};5;{}m", base + 8, index)
+        }
+        ColorDepth::Ansi16 if index < 16 => write_ansi16(w, index, base),
+        ColorDepth::Ansi16 => {
+            let (r, g, b) = indexed_rgb(index);
+            write_ansi16(w, ansi16(r, g, b), base)
+        }
+        ColorDepth::None => Ok(()),
+    }
+}
+
+/// Write an RGB color at the given color depth, `base` is 30 for foreground and 40 for background
+fn write_rgb<W: io::Write>(
+    w: &mut W,
+    (r, g, b): (u8, u8, u8),
+    base: u8,
+    depth: ColorDepth,
+) -> io::Result<()> {
+    match depth {
+        ColorDepth::TrueColor => write!(w, "\x1B[{};2;{};{};{}m", base + 8, r, g, b),
+        ColorDepth::Ansi256 => write!(w, "\x1B[{};5;{}m", base + 8, ansi256(r, g, b)),
+        ColorDepth::Ansi16 => write_ansi16(w, ansi16(r, g, b), base),
+        ColorDepth::None => Ok(()),
+    }
+}
+
+fn distance((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> u32 {
+    let d = |a: u8, b: u8| (i32::from(a) - i32::from(b)).pow(2) as u32;
+    d(r1, r2) + d(g1, g2) + d(b1, b2)
+}
+
+/// Nearest color of the xterm 256 color palette, from its color cube or its grayscale ramp
+fn ansi256(r: u8, g: u8, b: u8) -> u8 {
+    const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    let level = |v: u8| match v {
+        0..=47 => 0,
+        48..=114 => 1,
+        v => (v - 35) / 40,
+    };
+
+    let (ri, gi, bi) = (level(r), level(g), level(b));
+    let cube = (
+        LEVELS[ri as usize],
+        LEVELS[gi as usize],
+        LEVELS[bi as usize],
+    );
+
+    let average = ((u16::from(r) + u16::from(g) + u16::from(b)) / 3) as u8;
+    let gray_index = if average > 238 {
+        23
+    } else {
+        average.saturating_sub(3) / 10
+    };
+    let gray = 8 + 10 * gray_index;
+
+    if distance((gray, gray, gray), (r, g, b)) < distance(cube, (r, g, b)) {
+        232 + gray_index
+    } else {
+        16 + 36 * ri + 6 * gi + bi
+    }
+}
+
+/// The 16 standard and bright colors of the default xterm palette
+const PALETTE: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+/// RGB value of a color from the color cube or grayscale ramp of the 256 color table
+fn indexed_rgb(index: u8) -> (u8, u8, u8) {
+    const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    match index {
+        0..=15 => PALETTE[index as usize],
+        16..=231 => {
+            let i = index - 16;
+            (
+                LEVELS[(i / 36) as usize],
+                LEVELS[(i / 6 % 6) as usize],
+                LEVELS[(i % 6) as usize],
+            )
+        }
+        _ => {
+            let gray = 8 + 10 * (index - 232);
+            (gray, gray, gray)
+        }
+    }
+}
+
+/// Nearest of the 16 standard and bright colors, using the default xterm palette
+fn ansi16(r: u8, g: u8, b: u8) -> u8 {
+    (0..16)
+        .min_by_key(|&i| distance(PALETTE[i as usize], (r, g, b)))
+        .unwrap_or(0)
+}
+
 pub(crate) trait WriteStyle<W: io::Write> {
-    fn write_to(&self, w: &mut W, bash_prompt: bool) -> io::Result<()>;
-    fn write_difference(&self, w: &mut W, prev: &Self, bash_prompt: bool) -> io::Result<()>;
+    fn write_to(&self, w: &mut W, escapes: Escapes, depth: ColorDepth) -> io::Result<()>;
+    fn write_difference(
+        &self,
+        w: &mut W,
+        prev: &Self,
+        escapes: Escapes,
+        depth: ColorDepth,
+    ) -> io::Result<()>;
 }
 
 impl<W: io::Write> WriteStyle<W> for CompleteStyle {
-    fn write_to(&self, w: &mut W, bash_prompt: bool) -> io::Result<()> {
-        use Color::*;
+    fn write_to(&self, w: &mut W, escapes: Escapes, depth: ColorDepth) -> io::Result<()> {
+        write!(w, "{}", escapes.start())?;
 
-        if bash_prompt {
-            write!(w, "\u{01}")?;
-        }
+        // Without colors, only the text styles are written
+        let (fg, bg) = match depth {
+            ColorDepth::None => (None, None),
+            _ => (self.fg, self.bg),
+        };
 
         if self != &Default::default() {
-            if let Some(fg) = self.fg {
-                match fg {
-                    Black => write!(w, e!("30"))?,
-                    Red => write!(w, e!("31"))?,
-                    Green => write!(w, e!("32"))?,
-                    Yellow => write!(w, e!("33"))?,
-                    Blue => write!(w, e!("34"))?,
-                    Magenta => write!(w, e!("35"))?,
-                    Cyan => write!(w, e!("36"))?,
-                    White => write!(w, e!("37"))?,
-                    RGB(r, g, b) => write!(w, e!("38", "2", "{};{};{}"), r, g, b)?,
-                }
+            if let Some(fg) = fg {
+                write_color(w, fg, 30, depth)?;
             }
 
-            if let Some(bg) = self.bg {
-                match bg {
-                    Black => write!(w, e!("40"))?,
-                    Red => write!(w, e!("41"))?,
-                    Green => write!(w, e!("42"))?,
-                    Yellow => write!(w, e!("43"))?,
-                    Blue => write!(w, e!("44"))?,
-                    Magenta => write!(w, e!("45"))?,
-                    Cyan => write!(w, e!("46"))?,
-                    White => write!(w, e!("47"))?,
-                    RGB(r, g, b) => write!(w, e!("48", "2", "{};{};{}"), r, g, b)?,
-                }
+            if let Some(bg) = bg {
+                write_color(w, bg, 40, depth)?;
             }
 
             if self.bold {
@@ -67,29 +469,52 @@ impl<W: io::Write> WriteStyle<W> for CompleteStyle {
             if self.underline {
                 write!(w, e!("4"))?;
             }
+
+            if self.dim {
+                write!(w, e!("2"))?;
+            }
+
+            if self.blink {
+                write!(w, e!("5"))?;
+            }
+
+            if self.reverse {
+                write!(w, e!("7"))?;
+            }
+
+            if self.strikethrough {
+                write!(w, e!("9"))?;
+            }
+
+            if self.double_underline {
+                write!(w, e!("21"))?;
+            }
+
+            if self.overline {
+                write!(w, e!("53"))?;
+            }
         } else {
             write!(w, e!())?;
         }
 
-        if bash_prompt {
-            write!(w, "\u{02}")?;
-        }
+        write!(w, "{}", escapes.end())?;
 
         Ok(())
     }
 
-    fn write_difference(&self, w: &mut W, prev: &Self, bash_prompt: bool) -> io::Result<()> {
-        match Difference::between(&prev, &self) {
-            Difference::Add(style) => style.write_to(w, bash_prompt)?,
+    fn write_difference(
+        &self,
+        w: &mut W,
+        prev: &Self,
+        escapes: Escapes,
+        depth: ColorDepth,
+    ) -> io::Result<()> {
+        match Difference::between(prev, self) {
+            Difference::Add(style) => style.write_to(w, escapes, depth)?,
             Difference::Reset => {
-                if bash_prompt {
-                    write!(w, concat!["\u{01}", e!()])?;
-                    self.write_to(w, false)?;
-                    write!(w, "\u{02}")?;
-                } else {
-                    write!(w, e!())?;
-                    self.write_to(w, false)?;
-                }
+                write!(w, concat!["{}", e!()], escapes.start())?;
+                self.write_to(w, Escapes::None, depth)?;
+                write!(w, "{}", escapes.end())?;
             }
             Difference::None => { /* Do nothing! */ }
         };
@@ -115,6 +540,12 @@ impl Difference {
             || (prev.bold && !next.bold)
             || (prev.italics && !next.italics)
             || (prev.underline && !next.underline)
+            || (prev.dim && !next.dim)
+            || (prev.blink && !next.blink)
+            || (prev.reverse && !next.reverse)
+            || (prev.strikethrough && !next.strikethrough)
+            || (prev.double_underline && !next.double_underline)
+            || (prev.overline && !next.overline)
         {
             return Difference::Reset;
         }
@@ -125,6 +556,57 @@ impl Difference {
             bold: !prev.bold && next.bold,
             italics: !prev.italics && next.italics,
             underline: !prev.underline && next.underline,
+            dim: !prev.dim && next.dim,
+            blink: !prev.blink && next.blink,
+            reverse: !prev.reverse && next.reverse,
+            strikethrough: !prev.strikethrough && next.strikethrough,
+            double_underline: !prev.double_underline && next.double_underline,
+            overline: !prev.overline && next.overline,
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn detect_color_depth() {
+        use ColorDepth::*;
+
+        assert_eq!(ColorDepth::detect("truecolor", Some("xterm")), TrueColor);
+        assert_eq!(ColorDepth::detect("", Some("xterm-256color")), Ansi256);
+        assert_eq!(ColorDepth::detect("", Some("screen")), Ansi16);
+        assert_eq!(ColorDepth::detect("", Some("dumb")), None);
+        assert_eq!(ColorDepth::detect("", Option::None), TrueColor);
+    }
+
+    #[test]
+    fn nearest_colors() {
+        assert_eq!(ansi256(0, 0, 0), 16);
+        assert_eq!(ansi256(255, 255, 255), 231);
+        assert_eq!(ansi256(128, 128, 128), 244);
+        assert_eq!(ansi256(95, 135, 175), 67);
+        assert_eq!(ansi16(250, 10, 10), 9);
+        assert_eq!(ansi16(120, 120, 120), 8);
+    }
+
+    #[test]
+    fn indexed_colors() {
+        let render = |index, depth| {
+            let mut output = Vec::new();
+            write_indexed(&mut output, index, 40, depth).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(render(208, ColorDepth::TrueColor), "\x1B[48;5;208m");
+        assert_eq!(render(208, ColorDepth::Ansi256), "\x1B[48;5;208m");
+        assert_eq!(render(9, ColorDepth::Ansi16), "\x1B[101m");
+        assert_eq!(render(196, ColorDepth::Ansi16), "\x1B[101m");
+        assert_eq!(render(232, ColorDepth::Ansi16), "\x1B[40m");
+        assert_eq!(render(208, ColorDepth::None), "");
+
+        assert_eq!(indexed_rgb(67), (95, 135, 175));
+        assert_eq!(indexed_rgb(244), (128, 128, 128));
+    }
+}
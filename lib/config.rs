@@ -0,0 +1,328 @@
+//! Configuration file with named format presets
+//!
+//! The configuration is read from `$XDG_CONFIG_HOME/glit/config.toml`, and keys are named after
+//! the command-line flags they provide defaults for:
+//!
+//! ```toml
+//! # Format used in git repositories when no format or preset is given
+//! format = "b [MARD]"
+//! # Format used outside of git repositories
+//! else-format = "'$ '"
+//! # Escape format characters for a shell, one of bash, zsh, tcsh or none
+//! shell = "bash"
+//!
+//! # Named formats, selected with --preset
+//! [presets]
+//! compact = "[#c*(b)@#c(B):{+,-}]"
+//!
+//! # Formats for repositories whose working directory matches a glob
+//! [[repos]]
+//! path = "~/work/monorepo"
+//! preset = "compact"
+//! ```
+//!
+//! Repositories can also choose their own format in their git config, see
+//! [`GitConfig`](struct.GitConfig.html).
+
+use crate::interpreter::{ColorChoice, ColorDepth, Escapes};
+
+use git2::Repository;
+use glob::Pattern;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Formats and default flags read from a configuration file
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// Format used in git repositories when no format or preset is given
+    pub format: Option<String>,
+    /// Format used outside of git repositories
+    pub else_format: Option<String>,
+    /// Escape format characters for bash shell prompts
+    pub bash_escapes: bool,
+    /// Escape format characters for the prompt of a shell, takes precedence over `bash-escapes`
+    pub shell: Option<Escapes>,
+    /// When to use colors: always, auto, or never
+    pub color: Option<ColorChoice>,
+    /// Colors which the terminal is able to show, detected from the environment by default
+    pub color_depth: Option<ColorDepth>,
+    /// Stop reading stats from the repository after this many milliseconds
+    pub timeout: Option<u64>,
+    /// Named formats, selected with `--preset`
+    pub presets: BTreeMap<String, String>,
+    /// Formats for repositories whose working directory matches a glob, the first match is used
+    pub repos: Vec<RepoConfig>,
+}
+
+/// Format used for repositories whose working directory matches a glob
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RepoConfig {
+    /// Glob matched against the working directory, where a leading `~` is the home directory
+    pub path: String,
+    /// Format used in matching repositories
+    pub format: Option<String>,
+    /// Name of the preset used in matching repositories, when there is no format
+    pub preset: Option<String>,
+}
+
+/// Formats a repository chooses for itself in the `glit` section of its git config
+///
+/// ```sh
+/// git config glit.format "b [MARD]"
+/// git config glit.elseFormat "'$ '"
+/// git config glit.disable true
+/// ```
+///
+/// A disabled repository is formatted as if it were not a git repository, with the else-format.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GitConfig {
+    /// `glit.format`, format used in the repository
+    pub format: Option<String>,
+    /// `glit.elseFormat`, format used outside of git repositories or when disabled
+    pub else_format: Option<String>,
+    /// `glit.disable`, whether to treat the repository as if it were not a git repository
+    pub disable: bool,
+}
+
+impl GitConfig {
+    /// Read the `glit` section of the git config of a repository
+    pub fn from_repository(repo: &Repository) -> Result<GitConfig, git2::Error> {
+        GitConfig::from_config(&repo.config()?)
+    }
+
+    /// Read the `glit` section of the global and system git config, for use outside repositories
+    pub fn from_default() -> Result<GitConfig, git2::Error> {
+        GitConfig::from_config(&git2::Config::open_default()?)
+    }
+
+    pub fn from_config(config: &git2::Config) -> Result<GitConfig, git2::Error> {
+        // Missing keys are reported as errors which must be told apart from invalid values
+        fn optional<T>(value: Result<T, git2::Error>) -> Result<Option<T>, git2::Error> {
+            match value {
+                Ok(value) => Ok(Some(value)),
+                Err(ref e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+                Err(e) => Err(e),
+            }
+        }
+
+        Ok(GitConfig {
+            format: optional(config.get_string("glit.format"))?,
+            else_format: optional(config.get_string("glit.elseFormat"))?,
+            disable: optional(config.get_bool("glit.disable"))?.unwrap_or(false),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// Unable to read the configuration file
+    Io(PathBuf, io::Error),
+    /// The configuration file is not valid
+    Toml(PathBuf, toml::de::Error),
+    /// A repository path is not a valid glob
+    Glob(String, glob::PatternError),
+    /// A preset was selected which is not defined
+    UnknownPreset(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use ConfigError::*;
+        match self {
+            Io(p, e) => write!(f, "Unable to read `{}`: {}", p.to_string_lossy(), e),
+            Toml(p, e) => write!(
+                f,
+                "Invalid configuration in `{}`: {}",
+                p.to_string_lossy(),
+                e
+            ),
+            Glob(glob, e) => write!(f, "Invalid repository path `{}`: {}", glob, e),
+            UnknownPreset(name) => write!(f, "No preset named `{}` is defined", name),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Default location of the configuration file
+///
+/// Uses `$XDG_CONFIG_HOME/glit/config.toml`, falling back to `~/.config/glit/config.toml`.
+pub fn config_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| home_dir().map(|home| home.join(".config")))
+        .map(|config| config.join("glit").join("config.toml"))
+}
+
+fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+}
+
+impl Config {
+    /// Load the configuration from the default location, if there is a configuration file
+    pub fn load() -> Result<Config, ConfigError> {
+        match config_path() {
+            Some(path) if path.exists() => Config::from_path(&path),
+            _ => Ok(Config::default()),
+        }
+    }
+
+    /// Load the configuration from a file
+    pub fn from_path(path: &Path) -> Result<Config, ConfigError> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+        Config::from_toml(&contents, path)
+    }
+
+    /// Read the configuration from TOML, checking that repository paths are valid globs
+    ///
+    /// The path is only used to describe errors.
+    pub fn from_toml(contents: &str, path: &Path) -> Result<Config, ConfigError> {
+        let config: Config =
+            toml::from_str(contents).map_err(|e| ConfigError::Toml(path.to_owned(), e))?;
+        for repo in &config.repos {
+            repo.pattern()?;
+        }
+        Ok(config)
+    }
+
+    /// Choose the format used in a git repository
+    ///
+    /// A preset selected by name is used first, then the first repository override whose glob
+    /// matches the working directory, and finally the default format.
+    pub fn git_format(
+        &self,
+        preset: Option<&str>,
+        workdir: Option<&Path>,
+    ) -> Result<Option<String>, ConfigError> {
+        if let Some(name) = preset {
+            return self.preset(name).map(Some);
+        }
+
+        if let Some(workdir) = workdir {
+            // Collecting the components drops the trailing separator of the working directory
+            let workdir: PathBuf = workdir.components().collect();
+            for repo in &self.repos {
+                if !repo.pattern()?.matches_path(&workdir) {
+                    continue;
+                }
+                match (&repo.format, &repo.preset) {
+                    (Some(format), _) => return Ok(Some(format.clone())),
+                    (None, Some(name)) => return self.preset(name).map(Some),
+                    (None, None) => {}
+                }
+            }
+        }
+
+        Ok(self.format.clone())
+    }
+
+    /// Look up a named format
+    pub fn preset(&self, name: &str) -> Result<String, ConfigError> {
+        self.presets
+            .get(name)
+            .cloned()
+            .ok_or_else(|| ConfigError::UnknownPreset(name.to_owned()))
+    }
+}
+
+impl RepoConfig {
+    /// Glob for the working directory, with a leading `~` expanded to the home directory
+    fn pattern(&self) -> Result<Pattern, ConfigError> {
+        let path = match (self.path.strip_prefix('~'), home_dir()) {
+            (Some(rest), Some(home)) => format!("{}{}", home.to_string_lossy(), rest),
+            _ => self.path.clone(),
+        };
+        Pattern::new(&path).map_err(|e| ConfigError::Glob(self.path.clone(), e))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        format = "b"
+        bash-escapes = true
+        shell = "zsh"
+        color-depth = "256"
+        color = "never"
+
+        [presets]
+        compact = "[b]"
+
+        [[repos]]
+        path = "/work/mono*"
+        format = "b@B"
+
+        [[repos]]
+        path = "/work/**"
+        preset = "compact"
+    "#;
+
+    #[test]
+    fn git_format() {
+        let config = Config::from_toml(CONFIG, Path::new("config.toml")).unwrap();
+        assert!(config.bash_escapes);
+        assert_eq!(config.shell, Some(Escapes::Zsh));
+        assert_eq!(config.color_depth, Some(ColorDepth::Ansi256));
+        assert_eq!(config.color, Some(ColorChoice::Never));
+
+        let format = |preset, workdir: &str| {
+            config
+                .git_format(preset, Some(Path::new(workdir)))
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(format(None, "/home/repo/"), "b");
+        assert_eq!(format(None, "/work/monorepo/"), "b@B");
+        assert_eq!(format(None, "/work/other/"), "[b]");
+        assert_eq!(format(Some("compact"), "/work/monorepo/"), "[b]");
+    }
+
+    #[test]
+    fn errors() {
+        let config = Config::from_toml(CONFIG, Path::new("config.toml")).unwrap();
+        match config.git_format(Some("missing"), None) {
+            Err(ConfigError::UnknownPreset(name)) => assert_eq!(name, "missing"),
+            other => panic!("expected unknown preset, found {:?}", other),
+        }
+
+        match Config::from_toml("[[repos]]\npath = \"[\"", Path::new("config.toml")) {
+            Err(ConfigError::Glob(path, _)) => assert_eq!(path, "["),
+            other => panic!("expected invalid glob, found {:?}", other),
+        }
+
+        match Config::from_toml("formats = 1", Path::new("config.toml")) {
+            Err(ConfigError::Toml(..)) => {}
+            other => panic!("expected invalid toml, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn git_config() {
+        let path = std::env::temp_dir().join(format!("glit-config-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "[glit]\n\tformat = b@B\n\telseFormat = '$ '\n\tdisable = yes\n",
+        )
+        .unwrap();
+        let config = git2::Config::open(&path).unwrap();
+        let read = GitConfig::from_config(&config);
+        std::fs::remove_file(&path).unwrap();
+
+        let expect = GitConfig {
+            format: Some("b@B".to_owned()),
+            else_format: Some("'$ '".to_owned()),
+            disable: true,
+        };
+        assert_eq!(read.unwrap(), expect);
+    }
+}
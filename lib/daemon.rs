@@ -0,0 +1,452 @@
+//! Background daemon which caches stats for each repository
+//!
+//! The daemon keeps a handle to every repository it has been asked about, along with the stats it
+//! last read from it, and watches the working tree and git directory for changes.  Stats are only
+//! read again once something in the repository changes, so answering a prompt is nearly instant.
+//!
+//! `glit` talks to the daemon over a unix socket, one line of JSON in each direction: a
+//! [`Request`](../git/struct.Request.html) for a repository, and the resulting
+//! [`Stats`](../git/struct.Stats.html).
+
+use crate::git::{Request, Stats};
+
+use git2::Repository;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long `glit` waits for the daemon to answer, or half of its timeout if that is shorter
+///
+/// Reading the stats directly is always possible, so a daemon which is slow to answer should not
+/// slow down the prompt by much.
+pub const QUERY_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// How long the daemon waits on a single connection to send its query or receive the stats
+const STREAM_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Number of repositories the daemon caches stats for at once
+const MAX_REPOS: usize = 64;
+
+/// How long the stats of a repository are cached after it was last queried
+const MAX_IDLE: Duration = Duration::from_secs(60 * 60);
+
+/// A query for the stats of a repository sent to the daemon
+#[derive(Debug, Serialize, Deserialize)]
+struct Query {
+    /// Path to the git directory of the repository
+    path: PathBuf,
+    /// Stats which the format refers to
+    request: Request,
+}
+
+/// Stats cached for a single repository
+struct Cached {
+    repo: Repository,
+    /// Directories which are watched for changes to the repository
+    watched: Vec<PathBuf>,
+    /// Number of changes to the repository, counted by the watcher as they happen
+    changes: Arc<AtomicUsize>,
+    /// Number of changes which had been counted before the stats were read
+    read: Option<usize>,
+    /// Every stat which has been requested for the repository so far
+    request: Request,
+    stats: Stats,
+    last_query: Instant,
+}
+
+/// Counters of the changes to each watched directory, shared with the watcher
+type Watched = Arc<Mutex<Vec<(PathBuf, Arc<AtomicUsize>)>>>;
+
+/// Default location of the socket the daemon listens on
+///
+/// Uses `$XDG_RUNTIME_DIR/glit.sock` when available, and a socket in a directory of the user in
+/// the temporary directory otherwise.
+pub fn socket_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("glit.sock"),
+        None => std::env::temp_dir()
+            .join(format!("glit-{}", current_uid()))
+            .join("daemon.sock"),
+    }
+}
+
+fn current_uid() -> u32 {
+    // SAFETY: `getuid` has no preconditions and always succeeds
+    unsafe { libc::getuid() }
+}
+
+/// Directory which contains the socket
+fn socket_dir(socket: &Path) -> &Path {
+    match socket.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// Check that no other user can listen on the socket
+///
+/// Otherwise another user could create the socket before the daemon starts, and serve fake stats
+/// to every prompt.  The directory of the socket must belong to the current user and be closed to
+/// everyone else, and so must the socket itself if it exists.
+fn check_private(socket: &Path) -> io::Result<()> {
+    let uid = current_uid();
+    let denied = |path: &Path| {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} must belong to the current user and be private to them",
+                path.display()
+            ),
+        ))
+    };
+
+    let dir = socket_dir(socket);
+    let metadata = fs::metadata(dir)?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return denied(dir);
+    }
+
+    match fs::symlink_metadata(socket) {
+        Ok(metadata) if metadata.uid() != uid || metadata.mode() & 0o077 != 0 => denied(socket),
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Ask a running daemon for the stats of a repository
+///
+/// Fails when no daemon is listening on the socket, when the socket could belong to another user,
+/// or when the daemon does not answer before the timeout, in which case the stats should be read
+/// directly from the repository instead.
+pub fn query(
+    socket: &Path,
+    repo: &Repository,
+    request: &Request,
+    timeout: Duration,
+) -> io::Result<Stats> {
+    // A zero timeout is rejected by the socket, so wait at least a millisecond
+    let timeout = Some(timeout.max(Duration::from_millis(1)));
+
+    check_private(socket)?;
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+
+    let query = Query {
+        path: repo.path().to_owned(),
+        request: *request,
+    };
+    serde_json::to_writer(&mut stream, &query)?;
+    stream.write_all(b"\n")?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// Listen for queries on the socket until the process is stopped
+///
+/// Creates the directory of the socket when it does not exist, so that only the current user can
+/// access it.  Replaces a stale socket left behind by a daemon which is no longer running, but
+/// fails if another daemon is still listening on it.
+pub fn serve(socket: &Path) -> io::Result<()> {
+    match fs::DirBuilder::new().mode(0o700).create(socket_dir(socket)) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+    check_private(socket)?;
+
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("a daemon is already listening on {}", socket.display()),
+            ));
+        }
+        fs::remove_file(socket)?;
+    }
+
+    let listener = UnixListener::bind(socket)?;
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o600))?;
+
+    let mut daemon = Daemon::new()?;
+    for stream in listener.incoming() {
+        // Connections are answered one at a time, so one which never sends its query must not
+        // keep the daemon from answering the rest
+        let answer = stream.and_then(|stream| {
+            stream.set_read_timeout(Some(STREAM_TIMEOUT))?;
+            stream.set_write_timeout(Some(STREAM_TIMEOUT))?;
+            daemon.answer(stream)
+        });
+        if let Err(e) = answer {
+            eprintln!("glit daemon: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+struct Daemon {
+    repos: HashMap<PathBuf, Cached>,
+    watcher: RecommendedWatcher,
+    watched: Watched,
+}
+
+impl Daemon {
+    fn new() -> io::Result<Self> {
+        let watched = Watched::default();
+
+        // Count changes as soon as the watcher sees them, rather than when the next query
+        // arrives, so changes made while stats are read are not lost
+        let counters = Arc::clone(&watched);
+        let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            if let Ok(event) = event {
+                // Reading the repository must not make its stats stale
+                if event.kind.is_access() {
+                    return;
+                }
+                let counters = counters.lock().unwrap_or_else(|e| e.into_inner());
+                for path in &event.paths {
+                    for (dir, changes) in counters.iter() {
+                        if path.starts_with(dir) {
+                            changes.fetch_add(1, Ordering::SeqCst);
+                        }
+                    }
+                }
+            }
+        })
+        .map_err(io::Error::other)?;
+
+        Ok(Daemon {
+            repos: HashMap::new(),
+            watcher,
+            watched,
+        })
+    }
+
+    /// Read a single query from the stream and write back the stats
+    fn answer(&mut self, stream: UnixStream) -> io::Result<()> {
+        let mut line = String::new();
+        // Connecting without a query only checks whether the daemon is running
+        if BufReader::new(&stream).read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let query: Query = serde_json::from_str(&line)?;
+
+        let stats = self.stats(&query.path, &query.request)?;
+
+        let mut stream = &stream;
+        serde_json::to_writer(&mut stream, stats)?;
+        stream.write_all(b"\n")
+    }
+
+    /// Get the stats of a repository, reading them again only when necessary
+    fn stats(&mut self, path: &Path, request: &Request) -> io::Result<&Stats> {
+        let path = fs::canonicalize(path)?;
+        self.evict(&path);
+        if !self.repos.contains_key(&path) {
+            let cached = self.open(&path)?;
+            self.repos.insert(path.clone(), cached);
+        }
+
+        let cached = self
+            .repos
+            .get_mut(&path)
+            .expect("repository was just cached");
+        cached.last_query = Instant::now();
+
+        // Changes made while the stats are read are counted after `changes` was loaded, so they
+        // make the stats stale for the next query
+        let changes = cached.changes.load(Ordering::SeqCst);
+        if cached.read != Some(changes) || !cached.request.contains(request) {
+            cached.request.insert(request);
+            cached.stats = Stats::with_request(&mut cached.repo, &cached.request);
+            cached.read = Some(changes);
+        }
+
+        Ok(&cached.stats)
+    }
+
+    /// Stop caching repositories which have not been queried in a while, or which no longer
+    /// exist, and the least recently queried repositories when there are too many
+    ///
+    /// The repository at `keep` is about to be queried, so it is never evicted.
+    fn evict(&mut self, keep: &Path) {
+        let now = Instant::now();
+        let mut evicted: Vec<PathBuf> = self
+            .repos
+            .iter()
+            .filter(|(path, cached)| {
+                *path != keep
+                    && (now.duration_since(cached.last_query) > MAX_IDLE || !path.exists())
+            })
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        let mut remaining: Vec<_> = self
+            .repos
+            .iter()
+            .filter(|(path, _)| *path != keep && !evicted.contains(path))
+            .map(|(path, cached)| (cached.last_query, path.clone()))
+            .collect();
+        // Leave room for the repository which is about to be queried
+        let excess = (remaining.len() + 1).saturating_sub(MAX_REPOS);
+        remaining.sort();
+        evicted.extend(remaining.into_iter().take(excess).map(|(_, path)| path));
+
+        for path in evicted {
+            if let Some(cached) = self.repos.remove(&path) {
+                self.forget(&cached);
+            }
+        }
+    }
+
+    /// Stop watching the directories of a repository which is no longer cached
+    fn forget(&mut self, cached: &Cached) {
+        self.watched
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|(_, changes)| !Arc::ptr_eq(changes, &cached.changes));
+
+        for dir in &cached.watched {
+            // Another repository may be watching the same directory, like a linked worktree
+            let shared = self.repos.values().any(|other| other.watched.contains(dir));
+            if !shared {
+                let _ = self.watcher.unwatch(dir);
+            }
+        }
+    }
+
+    /// Open a repository and start watching it for changes
+    fn open(&mut self, path: &Path) -> io::Result<Cached> {
+        let repo = Repository::open(path).map_err(io::Error::other)?;
+
+        let mut watched = Vec::with_capacity(2);
+        if let Some(workdir) = repo.workdir() {
+            watched.push(workdir.to_owned());
+        }
+        // The git directory is usually inside of the working tree, except for linked worktrees
+        if !watched.iter().any(|dir| repo.path().starts_with(dir)) {
+            watched.push(repo.path().to_owned());
+        }
+
+        let changes = Arc::new(AtomicUsize::new(0));
+        for dir in &watched {
+            self.watcher
+                .watch(dir, RecursiveMode::Recursive)
+                .map_err(io::Error::other)?;
+            self.watched
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push((dir.clone(), Arc::clone(&changes)));
+        }
+
+        Ok(Cached {
+            repo,
+            watched,
+            changes,
+            read: None,
+            request: Request::default(),
+            stats: Stats::default(),
+            last_query: Instant::now(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    /// Create an empty repository in a temporary directory of its own
+    fn repository(name: &str) -> (PathBuf, Repository) {
+        let dir = std::env::temp_dir().join(format!("glit-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let repo = Repository::init(&dir).unwrap();
+        (dir, repo)
+    }
+
+    /// Start a daemon on a socket in a directory of its own, and wait until it is listening
+    fn start(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("glit-{}-{}.sock", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let socket = dir.join("daemon.sock");
+        let path = socket.clone();
+        thread::spawn(move || serve(&path));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while UnixStream::connect(&socket).is_err() {
+            assert!(Instant::now() < deadline, "daemon did not start listening");
+            thread::sleep(Duration::from_millis(10));
+        }
+        socket
+    }
+
+    #[test]
+    fn round_trip() {
+        let (dir, mut repo) = repository("daemon-round-trip");
+        fs::write(dir.join("untracked.rs"), "").unwrap();
+        let socket = start("daemon-round-trip");
+
+        // A connection which never sends its query must not keep others from being answered
+        let _idle = UnixStream::connect(&socket).unwrap();
+
+        let request = Request::all();
+        let stats = query(&socket, &repo, &request, Duration::from_secs(5)).unwrap();
+        assert_eq!(stats, Stats::with_request(&mut repo, &request));
+        assert_eq!(stats.untracked, 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stale_after_write() {
+        let (dir, repo) = repository("daemon-stale");
+        let socket = start("daemon-stale");
+
+        let request = Request::all();
+        let stats = query(&socket, &repo, &request, Duration::from_secs(5)).unwrap();
+        assert_eq!(stats.untracked, 0);
+
+        // The watcher sees the change shortly after the write, not at the same instant
+        fs::write(dir.join("untracked.rs"), "").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let stats = query(&socket, &repo, &request, Duration::from_secs(5)).unwrap();
+            if stats.untracked == 1 {
+                break;
+            }
+            assert!(Instant::now() < deadline, "stats were not read again");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shared_socket() {
+        let (dir, repo) = repository("daemon-shared");
+        let shared = dir.join("shared");
+        fs::create_dir(&shared).unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o777)).unwrap();
+        let socket = shared.join("daemon.sock");
+
+        // Anyone could have created a socket in the directory, so it is never trusted
+        let _listener = UnixListener::bind(&socket).unwrap();
+        let error = query(&socket, &repo, &Request::all(), Duration::from_secs(5)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        let error = serve(&socket).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
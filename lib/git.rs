@@ -1,10 +1,22 @@
+use crate::ast::{Name, Tree};
 use git2;
-use git2::{Branch, BranchType, Repository};
-use std::fmt::Write;
+use git2::{Branch, BranchType, Repository, RepositoryState};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt::{self, Write};
+use std::fs;
 use std::ops::{AddAssign, BitAnd};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
 
 /// Stats which the interpreter uses to populate the gist expression
-#[derive(Debug, PartialEq, Eq, Default, Clone)]
+///
+/// Stats can be serialized, for example to save them as a fixture for testing formats; when
+/// deserializing, missing fields are left empty.
+#[derive(Debug, PartialEq, Eq, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Stats {
     /// Number of untracked files which are new to the repository
     pub untracked: u16,
@@ -32,65 +44,371 @@ pub struct Stats {
     pub branch: String,
     /// The of the upstream branch
     pub remote: String,
+    /// Operation in progress in the repository, such as a rebase or merge
+    pub operation: Operation,
+    /// Step of the rebase in progress which is currently being applied
+    pub rebase_step: u16,
+    /// Total number of steps in the rebase in progress
+    pub rebase_total: u16,
+    /// Abbreviated id of the commit the rebase in progress is applied onto
+    pub rebase_onto: String,
+    /// Lines inserted in the working tree which have not yet been staged
+    ///
+    /// Only read when requested, see [`Request::diff`](struct.Request.html#structfield.diff)
+    pub insertions: u32,
+    /// Lines inserted in the index relative to HEAD
+    pub insertions_staged: u32,
+    /// Lines deleted in the working tree which have not yet been staged
+    pub deletions: u32,
+    /// Lines deleted in the index relative to HEAD
+    pub deletions_staged: u32,
+    /// Stats which were requested but could not be read in time
+    ///
+    /// Only set by [`Stats::with_deadline`](#method.with_deadline)
+    #[serde(skip_serializing_if = "Request::is_empty")]
+    pub missing: Request,
 }
 
 impl Stats {
     /// Populate stats with the status of the given repository
+    ///
+    /// Reads every stat except for the line counts read by
+    /// [`Stats::read_diff_stats`](#method.read_diff_stats).
     pub fn new(repo: &mut Repository) -> Stats {
+        Stats::with_request(
+            repo,
+            &Request {
+                diff: false,
+                ..Request::all()
+            },
+        )
+    }
+
+    /// Populate only the requested stats from the given repository
+    ///
+    /// Stats which were not requested are left empty.
+    pub fn with_request(repo: &mut Repository, request: &Request) -> Stats {
+        let mut st: Stats = Default::default();
+
+        if request.branch || request.remote || request.ahead_behind {
+            st.read_branch(repo, request);
+        }
+
+        if request.operation || request.rebase {
+            st.operation = repo.state().into();
+            if request.rebase && st.operation.is_rebase() {
+                st.read_rebase_progress(repo);
+            }
+        }
+
+        if request.index || request.worktree || request.untracked {
+            st.read_status(repo, request);
+        }
+
+        if request.stashes {
+            let _ = repo.stash_foreach(|_, &_, &_| {
+                st.stashes += 1;
+                true
+            });
+        }
+
+        if request.diff {
+            st.read_diff_stats(repo);
+        }
+
+        st
+    }
+
+    /// Populate the requested stats, giving up on those which are not read before the deadline
+    ///
+    /// Stats are read on a separate thread with its own handle to the repository, from cheapest
+    /// to most expensive; any part of the request which has not been read by the deadline is
+    /// left empty and marked in [`missing`](#structfield.missing).
+    ///
+    /// The thread is not joined or cancelled: a part which is still being read when the deadline
+    /// passes is finished in the background, but no more parts are started after the deadline.
+    pub fn with_deadline(repo: &Repository, request: &Request, deadline: Instant) -> Stats {
+        let (tx, rx) = mpsc::channel();
+        let path = repo.workdir().unwrap_or_else(|| repo.path()).to_owned();
+        let parts = request.split();
+
+        thread::spawn(move || {
+            let mut repo = match Repository::open(path) {
+                Ok(repo) => repo,
+                Err(_) => return,
+            };
+            for part in parts {
+                if Instant::now() >= deadline {
+                    return;
+                }
+                let stats = Stats::with_request(&mut repo, &part);
+                // Stop reading once nobody is waiting for the results
+                if tx.send((part, stats)).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let mut st = Stats {
+            missing: *request,
+            ..Default::default()
+        };
+
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            match rx.recv_timeout(timeout) {
+                Ok((part, stats)) => {
+                    st.take(&part, stats);
+                    st.missing.remove(&part);
+                }
+                // Either the deadline passed or every part was read
+                Err(_) => break,
+            }
+        }
+
+        st
+    }
+
+    /// Whether every requested stat was read
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+
+    /// Read stats from the output of `git status --porcelain=v2 --branch`
+    ///
+    /// Accepts output terminated by newlines or, with `-z`, by NUL characters, and reads the
+    /// number of stashes when `--show-stash` is given.  Unknown headers are ignored as git
+    /// recommends, but unrecognized entries are an error.  The porcelain format does not include
+    /// the operation in progress or line counts, so those are always left empty.
+    ///
+    /// ```
+    /// use glitter_lang::git::Stats;
+    ///
+    /// let status = concat!(
+    ///     "# branch.oid 1f2a3b4c5d6e\n",
+    ///     "# branch.head master\n",
+    ///     "# branch.upstream origin/master\n",
+    ///     "# branch.ab +1 -2\n",
+    ///     "? new.txt\n",
+    /// );
+    /// let stats = Stats::from_porcelain(status).unwrap();
+    /// assert_eq!(stats.branch, "master");
+    /// assert_eq!((stats.ahead, stats.behind, stats.untracked), (1, 2, 1));
+    /// ```
+    pub fn from_porcelain(status: &str) -> Result<Stats, PorcelainError> {
         let mut st: Stats = Default::default();
+        let mut oid = "";
+        let mut detached = false;
+
+        let nul_terminated = status.contains('\0');
+        let mut entries = status
+            .split(if nul_terminated { '\0' } else { '\n' })
+            .enumerate();
+
+        while let Some((i, entry)) = entries.next() {
+            // Output captured on Windows ends its lines with CRLF
+            let entry = if nul_terminated {
+                entry
+            } else {
+                entry.strip_suffix('\r').unwrap_or(entry)
+            };
+            let error = || PorcelainError {
+                line: i + 1,
+                entry: entry.to_string(),
+            };
+            let number = |n: Option<&str>, sign: char| -> Result<u16, PorcelainError> {
+                n.map(|n| n.trim_start_matches(sign))
+                    .and_then(|n| n.parse().ok())
+                    .ok_or_else(error)
+            };
+
+            let mut fields = entry.split(' ');
+            match fields.next() {
+                Some("#") => match fields.next() {
+                    Some("branch.oid") => oid = fields.next().ok_or_else(error)?,
+                    Some("branch.head") => match fields.next() {
+                        Some("(detached)") => detached = true,
+                        Some(head) => st.branch = head.to_string(),
+                        None => return Err(error()),
+                    },
+                    Some("branch.upstream") => {
+                        st.remote = fields.next().ok_or_else(error)?.to_string()
+                    }
+                    Some("branch.ab") => {
+                        st.ahead = number(fields.next(), '+')?;
+                        st.behind = number(fields.next(), '-')?;
+                    }
+                    Some("stash") => st.stashes = number(fields.next(), ' ')?,
+                    _ => {}
+                },
+                Some(kind @ "1") | Some(kind @ "2") => {
+                    let mut xy = fields.next().unwrap_or("").chars();
+                    match (xy.next(), xy.next()) {
+                        (Some(x), Some(y)) => st.count_porcelain(x, y),
+                        _ => return Err(error()),
+                    }
+                    // With -z, the original path of a rename is a separate entry
+                    if kind == "2" && nul_terminated {
+                        entries.next();
+                    }
+                }
+                Some("u") => st.conflicts += 1,
+                Some("?") => st.untracked += 1,
+                Some("!") | Some("") | None => {}
+                Some(_) => return Err(error()),
+            }
+        }
+
+        // Mimic `read_branch`, which shows the start of the commit id in detached HEAD
+        if detached {
+            st.branch = oid.chars().take(8).collect();
+        }
+
+        Ok(st)
+    }
+
+    /// Count a single entry from porcelain status by its index and working tree status codes
+    fn count_porcelain(&mut self, index: char, worktree: char) {
+        match index {
+            'A' => self.added_staged += 1,
+            'M' => self.modified_staged += 1,
+            'D' => self.deleted_staged += 1,
+            'R' => self.renamed += 1,
+            _ => {}
+        }
+        match worktree {
+            'M' => self.modified += 1,
+            'D' => self.deleted += 1,
+            _ => {}
+        }
+    }
+
+    /// Take the stats covered by part of a request from another set of stats
+    fn take(&mut self, part: &Request, other: Stats) {
+        if part.branch {
+            self.branch = other.branch;
+        }
+        if part.remote {
+            self.remote = other.remote;
+        }
+        if part.ahead_behind {
+            self.ahead = other.ahead;
+            self.behind = other.behind;
+        }
+        if part.index {
+            self.added_staged = other.added_staged;
+            self.modified_staged = other.modified_staged;
+            self.renamed = other.renamed;
+            self.deleted_staged = other.deleted_staged;
+        }
+        if part.worktree {
+            self.modified = other.modified;
+            self.deleted = other.deleted;
+        }
+        if part.index || part.worktree {
+            self.conflicts = other.conflicts;
+        }
+        if part.untracked {
+            self.untracked = other.untracked;
+        }
+        if part.stashes {
+            self.stashes = other.stashes;
+        }
+        if part.operation {
+            self.operation = other.operation;
+        }
+        if part.rebase {
+            self.rebase_step = other.rebase_step;
+            self.rebase_total = other.rebase_total;
+            self.rebase_onto = other.rebase_onto;
+        }
+        if part.diff {
+            self.insertions = other.insertions;
+            self.insertions_staged = other.insertions_staged;
+            self.deletions = other.deletions;
+            self.deletions_staged = other.deletions_staged;
+        }
+    }
 
-        st.read_branch(repo);
+    /// Count the status of files in the index and working tree
+    ///
+    /// Skips the parts of the status which were not requested; scanning untracked files in
+    /// particular is expensive in large repositories.
+    fn read_status(&mut self, repo: &Repository, request: &Request) {
+        use git2::StatusShow;
 
         let mut opts = git2::StatusOptions::new();
 
-        opts.include_untracked(true)
-            .recurse_untracked_dirs(true)
-            .renames_head_to_index(true);
+        let workdir = request.worktree || request.untracked;
+        opts.show(match (request.index, workdir) {
+            (true, true) => StatusShow::IndexAndWorkdir,
+            (true, false) => StatusShow::Index,
+            (false, _) => StatusShow::Workdir,
+        })
+        .include_untracked(request.untracked)
+        .recurse_untracked_dirs(request.untracked)
+        .renames_head_to_index(request.index);
 
         if let Ok(statuses) = repo.statuses(Some(&mut opts)) {
             for status in statuses.iter() {
                 let flags = status.status();
 
                 if check(flags, git2::Status::WT_NEW) {
-                    st.untracked += 1;
+                    self.untracked += 1;
                 }
                 if check(flags, git2::Status::INDEX_NEW) {
-                    st.added_staged += 1;
+                    self.added_staged += 1;
                 }
                 if check(flags, git2::Status::WT_MODIFIED) {
-                    st.modified += 1;
+                    self.modified += 1;
                 }
                 if check(flags, git2::Status::INDEX_MODIFIED) {
-                    st.modified_staged += 1;
+                    self.modified_staged += 1;
                 }
                 if check(flags, git2::Status::INDEX_RENAMED) {
-                    st.renamed += 1;
+                    self.renamed += 1;
                 }
                 if check(flags, git2::Status::WT_DELETED) {
-                    st.deleted += 1;
+                    self.deleted += 1;
                 }
                 if check(flags, git2::Status::INDEX_DELETED) {
-                    st.deleted_staged += 1;
+                    self.deleted_staged += 1;
                 }
                 if check(flags, git2::Status::CONFLICTED) {
-                    st.conflicts += 1;
+                    self.conflicts += 1;
                 }
             }
         }
+    }
 
-        let _ = repo.stash_foreach(|_, &_, &_| {
-            st.stashes += 1;
-            true
-        });
+    /// Read the number of lines inserted and deleted in the index and working tree
+    ///
+    /// Diffing is much more expensive than reading the status, so these stats are not read by
+    /// [`Stats::new`](#method.new) and must be requested separately.
+    pub fn read_diff_stats(&mut self, repo: &Repository) {
+        let head = repo.head().ok().and_then(|head| head.peel_to_tree().ok());
 
-        st
+        if let Ok(stats) = repo
+            .diff_tree_to_index(head.as_ref(), None, None)
+            .and_then(|diff| diff.stats())
+        {
+            self.insertions_staged = saturate(stats.insertions());
+            self.deletions_staged = saturate(stats.deletions());
+        }
+
+        if let Ok(stats) = repo
+            .diff_index_to_workdir(None, None)
+            .and_then(|diff| diff.stats())
+        {
+            self.insertions = saturate(stats.insertions());
+            self.deletions = saturate(stats.deletions());
+        }
     }
 
     /// Read the branch-name of the repository
     ///
     /// If in detached head, grab the first few characters of the commit ID if possible, otherwise
     /// simply provide HEAD as the branch name.  This is to mimic the behaviour of `git status`.
-    fn read_branch(&mut self, repo: &Repository) {
+    fn read_branch(&mut self, repo: &Repository, request: &Request) {
         self.branch = match repo.head() {
             Ok(head) => {
                 if let Some(name) = head.shorthand() {
@@ -109,7 +427,9 @@ impl Stats {
                     } else {
                         let branch = name.to_string();
                         // Since we have a branch name, look for the name of the upstream branch
-                        self.read_upstream_name(repo, &branch);
+                        if request.remote || request.ahead_behind {
+                            self.read_upstream_name(repo, &branch, request);
+                        }
                         branch
                     }
                 } else {
@@ -122,8 +442,31 @@ impl Stats {
         };
     }
 
+    /// Read the progress of a rebase in progress
+    ///
+    /// Merge-based and interactive rebases keep their progress in `rebase-merge`, while `git am`
+    /// and apply-based rebases use `rebase-apply`; this mirrors what `git status` reports.
+    fn read_rebase_progress(&mut self, repo: &Repository) {
+        let merge = repo.path().join("rebase-merge");
+        let apply = repo.path().join("rebase-apply");
+
+        let (dir, step, total) = if merge.is_dir() {
+            (merge, "msgnum", "end")
+        } else if apply.is_dir() {
+            (apply, "next", "last")
+        } else {
+            return;
+        };
+
+        self.rebase_step = read_number(&dir.join(step)).unwrap_or(0);
+        self.rebase_total = read_number(&dir.join(total)).unwrap_or(0);
+        if let Ok(onto) = fs::read_to_string(dir.join("onto")) {
+            self.rebase_onto = onto.trim().chars().take(8).collect();
+        }
+    }
+
     /// Read name of the upstream branch
-    fn read_upstream_name(&mut self, repo: &Repository, branch: &str) {
+    fn read_upstream_name(&mut self, repo: &Repository, branch: &str, request: &Request) {
         // First grab branch from the name
         self.remote = match repo.find_branch(branch, BranchType::Local) {
             Ok(branch) => {
@@ -133,7 +476,9 @@ impl Stats {
                     Ok(upstream) => {
                         // While we have the upstream branch, traverse the graph and count
                         // ahead-behind commits.
-                        self.read_ahead_behind(repo, &branch, &upstream);
+                        if request.ahead_behind {
+                            self.read_ahead_behind(repo, &branch, &upstream);
+                        }
 
                         match upstream.name() {
                             Ok(Some(name)) => name.to_string(),
@@ -171,9 +516,291 @@ impl AddAssign for Stats {
         self.behind += rhs.behind;
         self.conflicts += rhs.conflicts;
         self.stashes += rhs.stashes;
+        self.insertions += rhs.insertions;
+        self.insertions_staged += rhs.insertions_staged;
+        self.deletions += rhs.deletions;
+        self.deletions_staged += rhs.deletions_staged;
+    }
+}
+
+/// The set of stats to read from a repository
+///
+/// Reading some stats, like the status of every file, is expensive in large repositories; a
+/// request built from a parsed format only reads the stats which the format refers to.
+///
+/// ```
+/// use glitter_lang::{git::Request, parser::parse};
+///
+/// let request = Request::from(&parse("b@B").unwrap());
+/// assert!(request.branch && request.remote);
+/// assert!(!request.index && !request.worktree && !request.untracked);
+/// ```
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Request {
+    /// Read the branch name
+    pub branch: bool,
+    /// Read the name of the upstream branch
+    pub remote: bool,
+    /// Count commits ahead and behind the upstream branch
+    pub ahead_behind: bool,
+    /// Count changes staged in the index
+    pub index: bool,
+    /// Count changes in the working tree which are not staged
+    pub worktree: bool,
+    /// Count untracked files
+    pub untracked: bool,
+    /// Count stashes
+    pub stashes: bool,
+    /// Read the operation in progress
+    pub operation: bool,
+    /// Read the progress of a rebase in progress
+    pub rebase: bool,
+    /// Count lines inserted and deleted in the index and working tree
+    pub diff: bool,
+}
+
+impl Request {
+    /// Request every stat
+    pub fn all() -> Self {
+        Request {
+            branch: true,
+            remote: true,
+            ahead_behind: true,
+            index: true,
+            worktree: true,
+            untracked: true,
+            stashes: true,
+            operation: true,
+            rebase: true,
+            diff: true,
+        }
+    }
+
+    /// Request the stats required to interpret a name
+    pub fn add(&mut self, name: Name) {
+        use Name::*;
+        match name {
+            Branch => self.branch = true,
+            Remote => self.remote = true,
+            Ahead | Behind => self.ahead_behind = true,
+            Conflict => {
+                self.index = true;
+                self.worktree = true;
+            }
+            Added | Modified | DeletedStaged | Renamed => self.index = true,
+            Unstaged | Deleted => self.worktree = true,
+            Untracked => self.untracked = true,
+            Stashed => self.stashes = true,
+            Operation => self.operation = true,
+            RebaseStep | RebaseTotal | RebaseOnto => self.rebase = true,
+            Insertions | InsertionsStaged | Deletions | DeletionsStaged => self.diff = true,
+            Incomplete | Quote => {}
+        }
+    }
+
+    /// Add the stats in another request to this request
+    pub fn insert(&mut self, other: &Request) {
+        self.branch |= other.branch;
+        self.remote |= other.remote;
+        self.ahead_behind |= other.ahead_behind;
+        self.index |= other.index;
+        self.worktree |= other.worktree;
+        self.untracked |= other.untracked;
+        self.stashes |= other.stashes;
+        self.operation |= other.operation;
+        self.rebase |= other.rebase;
+        self.diff |= other.diff;
+    }
+
+    /// Whether this request includes every stat in another request
+    pub fn contains(&self, other: &Request) -> bool {
+        let mut extra = *other;
+        extra.remove(self);
+        extra.is_empty()
+    }
+
+    /// Whether no stats are requested
+    pub fn is_empty(&self) -> bool {
+        *self == Request::default()
+    }
+
+    /// Remove the stats in another request from this request
+    pub fn remove(&mut self, other: &Request) {
+        self.branch &= !other.branch;
+        self.remote &= !other.remote;
+        self.ahead_behind &= !other.ahead_behind;
+        self.index &= !other.index;
+        self.worktree &= !other.worktree;
+        self.untracked &= !other.untracked;
+        self.stashes &= !other.stashes;
+        self.operation &= !other.operation;
+        self.rebase &= !other.rebase;
+        self.diff &= !other.diff;
+    }
+
+    /// Split the request into parts which can be read independently
+    ///
+    /// Parts are ordered roughly from cheapest to most expensive to read.
+    fn split(&self) -> Vec<Request> {
+        let none = Request::default();
+        let parts = [
+            Request {
+                branch: self.branch,
+                remote: self.remote,
+                ..none
+            },
+            Request {
+                operation: self.operation,
+                rebase: self.rebase,
+                ..none
+            },
+            Request {
+                stashes: self.stashes,
+                ..none
+            },
+            Request {
+                ahead_behind: self.ahead_behind,
+                ..none
+            },
+            Request {
+                index: self.index,
+                worktree: self.worktree,
+                untracked: self.untracked,
+                ..none
+            },
+            Request {
+                diff: self.diff,
+                ..none
+            },
+        ];
+
+        parts
+            .iter()
+            .filter(|part| **part != none)
+            .copied()
+            .collect()
+    }
+}
+
+impl<'a> From<&'a Tree> for Request {
+    fn from(tree: &'a Tree) -> Self {
+        let mut request = Request::default();
+        tree.for_each_name(&mut |name| request.add(name));
+        request
     }
 }
 
+/// An entry in `git status --porcelain=v2` output which could not be read
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PorcelainError {
+    /// Line of the entry in the output, starting from 1
+    pub line: usize,
+    /// Text of the entry which could not be read
+    pub entry: String,
+}
+
+impl fmt::Display for PorcelainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "unable to read line {} of porcelain status: {:?}",
+            self.line, self.entry
+        )
+    }
+}
+
+impl std::error::Error for PorcelainError {}
+
+/// An operation which is in progress in the repository
+///
+/// Mirrors the states reported by `git status` and the labels used by git's own `git-prompt.sh`.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, Serialize, Deserialize)]
+pub enum Operation {
+    /// No operation in progress
+    #[default]
+    None,
+    /// Merge in progress
+    Merge,
+    /// Revert in progress
+    Revert,
+    /// Cherry-pick in progress
+    CherryPick,
+    /// Bisect in progress
+    Bisect,
+    /// Rebase in progress
+    Rebase,
+    /// Interactive rebase in progress
+    RebaseInteractive,
+    /// Merge-based rebase in progress
+    RebaseMerge,
+    /// Applying patches from a mailbox with `git am`
+    ApplyMailbox,
+    /// Either a `git am` or a rebase in progress
+    ApplyMailboxOrRebase,
+}
+
+impl Operation {
+    pub fn as_str(&self) -> &'static str {
+        use Operation::*;
+        match self {
+            None => "",
+            Merge => "MERGING",
+            Revert => "REVERTING",
+            CherryPick => "CHERRY-PICKING",
+            Bisect => "BISECTING",
+            Rebase => "REBASE",
+            RebaseInteractive => "REBASE-i",
+            RebaseMerge => "REBASE-m",
+            ApplyMailbox => "AM",
+            ApplyMailboxOrRebase => "AM/REBASE",
+        }
+    }
+
+    /// Whether the operation keeps rebase progress in the git directory
+    pub fn is_rebase(&self) -> bool {
+        use Operation::*;
+        match self {
+            Rebase | RebaseInteractive | RebaseMerge | ApplyMailbox | ApplyMailboxOrRebase => true,
+            None | Merge | Revert | CherryPick | Bisect => false,
+        }
+    }
+}
+
+impl From<RepositoryState> for Operation {
+    fn from(state: RepositoryState) -> Self {
+        use RepositoryState::*;
+        match state {
+            Clean => Operation::None,
+            Merge => Operation::Merge,
+            Revert | RevertSequence => Operation::Revert,
+            CherryPick | CherryPickSequence => Operation::CherryPick,
+            Bisect => Operation::Bisect,
+            Rebase => Operation::Rebase,
+            RebaseInteractive => Operation::RebaseInteractive,
+            RebaseMerge => Operation::RebaseMerge,
+            ApplyMailbox => Operation::ApplyMailbox,
+            ApplyMailboxOrRebase => Operation::ApplyMailboxOrRebase,
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Read a file in the git directory which contains a single number
+fn read_number(path: &Path) -> Option<u16> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Convert a count to a stat, saturating counts which do not fit
+fn saturate(count: usize) -> u32 {
+    u32::try_from(count).unwrap_or(u32::MAX)
+}
+
 /// Check the bits of a flag against the value to see if they are set
 #[inline]
 fn check<B>(val: B, flag: B) -> bool
@@ -182,3 +809,91 @@ where
 {
     val & flag == flag
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn porcelain() {
+        let status = "\
+# branch.oid 1f2a3b4c5d6e7f8091a2b3c4d5e6f708192a3b4c
+# branch.head feature
+# branch.upstream origin/feature
+# branch.ab +3 -7
+# stash 2
+1 M. N... 100644 100644 100644 1234567 89abcdef staged.rs
+1 .M N... 100644 100644 100644 1234567 89abcdef unstaged.rs
+1 MD N... 100644 100644 000000 1234567 89abcdef both.rs
+1 A. N... 000000 100644 100644 0000000 89abcdef added.rs
+1 D. N... 100644 000000 000000 1234567 0000000 removed.rs
+2 R. N... 100644 100644 100644 1234567 1234567 R100 new name.rs\told name.rs
+u UU N... 100644 100644 100644 100644 1234567 89abcde fedcba9 conflict.rs
+? untracked.rs
+? other/
+! ignored.rs
+";
+        let expect = Stats {
+            branch: "feature".to_string(),
+            remote: "origin/feature".to_string(),
+            ahead: 3,
+            behind: 7,
+            stashes: 2,
+            modified_staged: 2,
+            modified: 1,
+            deleted: 1,
+            added_staged: 1,
+            deleted_staged: 1,
+            renamed: 1,
+            conflicts: 1,
+            untracked: 2,
+            ..Default::default()
+        };
+        assert_eq!(Stats::from_porcelain(status), Ok(expect.clone()));
+
+        // Same status, with the line endings of output captured on Windows
+        let crlf = status.replace('\n', "\r\n");
+        assert_eq!(Stats::from_porcelain(&crlf), Ok(expect.clone()));
+
+        // Same status, terminated with NUL characters as with `-z`
+        let status = status.replace(['\n', '\t'], "\0");
+        assert_eq!(Stats::from_porcelain(&status), Ok(expect));
+    }
+
+    #[test]
+    fn saturate_counts() {
+        assert_eq!(saturate(7), 7);
+        assert_eq!(saturate(usize::MAX), u32::MAX);
+    }
+
+    #[test]
+    fn deserialize_partial() {
+        let stats: Stats =
+            serde_json::from_str(r#"{"branch": "main", "behind": 12, "operation": "Merge"}"#)
+                .unwrap();
+        let expect = Stats {
+            branch: "main".to_string(),
+            behind: 12,
+            operation: Operation::Merge,
+            ..Default::default()
+        };
+        assert_eq!(stats, expect);
+    }
+
+    #[test]
+    fn porcelain_detached() {
+        let status =
+            "# branch.oid 1f2a3b4c5d6e7f8091a2b3c4d5e6f708192a3b4c\n# branch.head (detached)\n";
+        let stats = Stats::from_porcelain(status).unwrap();
+        assert_eq!(stats.branch, "1f2a3b4c");
+        assert_eq!(stats.remote, "");
+    }
+
+    #[test]
+    fn porcelain_error() {
+        let status = "# branch.head master\n# branch.ab +one -0\n";
+        let error = Stats::from_porcelain(status).unwrap_err();
+        assert_eq!(error.line, 2);
+        assert_eq!(error.entry, "# branch.ab +one -0");
+    }
+}
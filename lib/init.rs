@@ -0,0 +1,379 @@
+//! Shell integration scripts printed by `glit init <shell>`
+//!
+//! Each script sets the prompt of its shell to the output of `glit`, and falls back to the
+//! prompt which was set before when `glit` fails, for example outside of a git repository
+//! without an else-format.  The exit status of the last command is left untouched, so it can
+//! still be shown by the rest of the prompt.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Shells which `glit init` can integrate with
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    PowerShell,
+    Elvish,
+    Nushell,
+}
+
+impl Shell {
+    /// Names of every shell, as accepted by `FromStr`
+    pub const NAMES: [&'static str; 6] = ["bash", "zsh", "fish", "powershell", "elvish", "nushell"];
+
+    pub fn as_str(&self) -> &'static str {
+        use Shell::*;
+        match self {
+            Bash => "bash",
+            Zsh => "zsh",
+            Fish => "fish",
+            PowerShell => "powershell",
+            Elvish => "elvish",
+            Nushell => "nushell",
+        }
+    }
+
+    /// Whether the shell has a prompt on the right side of the line
+    pub fn has_right_prompt(&self) -> bool {
+        *self != Shell::Bash && *self != Shell::PowerShell
+    }
+
+    /// Flags which make the output of `glit` safe to use in the prompt of the shell
+    ///
+    /// The prompt captures the output of `glit`, so colors must be enabled explicitly for shells
+    /// without escapes.
+    fn flags(&self) -> &'static [&'static str] {
+        match self {
+            Shell::Bash => &["--shell", "bash"],
+            Shell::Zsh => &["--shell", "zsh"],
+            _ => &["--color", "always"],
+        }
+    }
+
+    /// Quote an argument so the shell passes it to `glit` unchanged
+    fn quote(&self, arg: &str) -> String {
+        use Shell::*;
+        match self {
+            Bash | Zsh => format!("'{}'", arg.replace('\'', r"'\''")),
+            Fish => format!("'{}'", arg.replace('\\', r"\\").replace('\'', r"\'")),
+            PowerShell | Elvish => format!("'{}'", arg.replace('\'', "''")),
+            Nushell => {
+                // Raw strings cannot escape anything, so use enough `#` to enclose the argument
+                let mut hashes = String::from("#");
+                while arg.contains(&format!("'{}", hashes)) {
+                    hashes.push('#');
+                }
+                format!("r{}'{}'{}", hashes, arg, hashes)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Shell {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// A name which is not one of `Shell::NAMES`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct UnknownShell(pub String);
+
+impl fmt::Display for UnknownShell {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "unknown shell `{}`, expected one of {}",
+            self.0,
+            Shell::NAMES.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for UnknownShell {}
+
+impl FromStr for Shell {
+    type Err = UnknownShell;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use Shell::*;
+        match s.to_lowercase().as_str() {
+            "bash" => Ok(Bash),
+            "zsh" => Ok(Zsh),
+            "fish" => Ok(Fish),
+            "powershell" | "pwsh" => Ok(PowerShell),
+            "elvish" => Ok(Elvish),
+            "nushell" | "nu" => Ok(Nushell),
+            _ => Err(UnknownShell(s.to_owned())),
+        }
+    }
+}
+
+/// Which prompt the output of `glit` is used for
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Prompt {
+    Left,
+    Right,
+}
+
+/// Integration script which sets a prompt of the shell to the output of `glit` with `args`
+///
+/// Returns `None` when the shell does not have a right prompt.
+pub fn script(shell: Shell, prompt: Prompt, args: &[&str]) -> Option<String> {
+    if prompt == Prompt::Right && !shell.has_right_prompt() {
+        return None;
+    }
+
+    let args: String = shell
+        .flags()
+        .iter()
+        .map(|flag| flag.to_string())
+        .chain(args.iter().map(|arg| shell.quote(arg)))
+        .map(|arg| format!(" {}", arg))
+        .collect();
+    let glit = format!("glit{}", args);
+
+    let right = prompt == Prompt::Right;
+    let script = match shell {
+        Shell::Bash => bash(&glit),
+        Shell::Zsh => zsh(&glit, if right { "RPROMPT" } else { "PROMPT" }),
+        Shell::Fish => fish(
+            &glit,
+            if right {
+                "fish_right_prompt"
+            } else {
+                "fish_prompt"
+            },
+        ),
+        Shell::PowerShell => powershell(&glit),
+        Shell::Elvish => elvish(&glit, if right { "rprompt" } else { "prompt" }),
+        Shell::Nushell => nushell(
+            &glit,
+            if right {
+                "PROMPT_COMMAND_RIGHT"
+            } else {
+                "PROMPT_COMMAND"
+            },
+        ),
+    };
+
+    Some(format!(
+        "# glitter prompt for {}, generated by `glit init {}`\n{}",
+        shell, shell, script
+    ))
+}
+
+fn bash(glit: &str) -> String {
+    format!(
+        r#"__glit_default_prompt="${{__glit_default_prompt-$PS1}}"
+
+__glit_prompt() {{
+    local status=$?
+    PS1="$({} 2>/dev/null)" || PS1="$__glit_default_prompt"
+    return $status
+}}
+
+if [[ ";$PROMPT_COMMAND;" != *";__glit_prompt;"* ]]; then
+    PROMPT_COMMAND="__glit_prompt${{PROMPT_COMMAND:+;$PROMPT_COMMAND}}"
+fi
+"#,
+        glit
+    )
+}
+
+fn zsh(glit: &str, var: &str) -> String {
+    format!(
+        r#"typeset -g __glit_default_{var}="${{__glit_default_{var}-${var}}}"
+
+__glit_{var}() {{
+    local glit_status=$?
+    {var}="$({glit} 2>/dev/null)" || {var}="$__glit_default_{var}"
+    return $glit_status
+}}
+
+autoload -Uz add-zsh-hook
+add-zsh-hook precmd __glit_{var}
+"#,
+        var = var,
+        glit = glit
+    )
+}
+
+fn fish(glit: &str, function: &str) -> String {
+    format!(
+        r#"if functions -q {function}; and not functions -q __glit_default_{function}
+    functions -c {function} __glit_default_{function}
+end
+
+function {function}
+    {glit} 2>/dev/null
+    or functions -q __glit_default_{function}
+    and __glit_default_{function}
+end
+"#,
+        function = function,
+        glit = glit
+    )
+}
+
+fn powershell(glit: &str) -> String {
+    format!(
+        r#"if (-not $global:__glit_default_prompt) {{
+    $global:__glit_default_prompt = $function:prompt
+}}
+
+function global:prompt {{
+    $glit_status = $global:LASTEXITCODE
+    $output = & {} 2>$null
+    if ($LASTEXITCODE -ne 0) {{
+        $output = & $global:__glit_default_prompt
+    }}
+    $global:LASTEXITCODE = $glit_status
+    $output -join "`n"
+}}
+"#,
+        glit
+    )
+}
+
+fn elvish(glit: &str, var: &str) -> String {
+    let glit = glit.replacen("glit", "e:glit", 1);
+    format!(
+        r#"use os
+use str
+
+var glit-default-{var} = $edit:{var}
+
+set edit:{var} = {{
+    try {{
+        print (str:trim-right ({glit} 2>$os:dev-null | slurp) "\n")
+    }} catch {{
+        $glit-default-{var}
+    }}
+}}
+"#,
+        var = var,
+        glit = glit
+    )
+}
+
+fn nushell(glit: &str, var: &str) -> String {
+    format!(
+        r#"$env.__glit_default_{var} = ($env.__glit_default_{var}? | default $env.{var}?)
+
+$env.{var} = {{||
+    let glit = (^{glit} | complete)
+    if $glit.exit_code == 0 {{
+        $glit.stdout | str trim --right --char "\n"
+    }} else {{
+        let default = $env.__glit_default_{var}?
+        if ($default | describe) == "closure" {{
+            do $default
+        }} else {{
+            $default | default ""
+        }}
+    }}
+}}
+"#,
+        var = var,
+        glit = glit
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ARGS: &[&str] = &["#g*(b)[+-'it''s']", "-e", r"'\w'"];
+
+    #[test]
+    fn names() {
+        for name in &Shell::NAMES {
+            assert_eq!(name.parse::<Shell>().unwrap().as_str(), *name);
+        }
+        assert!("tcsh".parse::<Shell>().is_err());
+    }
+
+    #[test]
+    fn right_prompt() {
+        assert_eq!(script(Shell::Bash, Prompt::Right, ARGS), None);
+        assert_eq!(script(Shell::PowerShell, Prompt::Right, ARGS), None);
+    }
+
+    #[test]
+    fn nushell_quotes() {
+        assert_eq!(Shell::Nushell.quote("'#'"), "r##''#''##");
+    }
+
+    /// Branch which bash would run if it were not escaped in its prompt
+    const HOSTILE_BRANCH: &str = "$(touch glit-pwned)`touch glit-pwned`\\$HOME";
+
+    /// Output of `glit --shell bash b` in a repository on the hostile branch
+    fn hostile_bash_prompt() -> String {
+        use crate::git::Stats;
+        use crate::interpreter::{Escapes, Interpreter};
+
+        let stats = Stats {
+            branch: HOSTILE_BRANCH.to_owned(),
+            ..Default::default()
+        };
+        let tree = crate::parser::parse("b").unwrap();
+        let mut output = Vec::new();
+        Interpreter::new(stats, false, Escapes::Bash)
+            .evaluate(&tree, &mut output)
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn bash_escapes() {
+        insta::assert_snapshot!(hostile_bash_prompt());
+    }
+
+    #[test]
+    fn bash_prompt_is_inert() {
+        use std::process::Command;
+
+        let dir = std::env::temp_dir().join(format!("glit-inert-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Expand the output the same way bash expands PS1, if bash 4.4 or later is available
+        let expanded = Command::new("bash")
+            .args(["-c", r#"shopt -s promptvars; PS1=$1; printf %s "${PS1@P}""#])
+            .args(["bash", &hostile_bash_prompt()])
+            .current_dir(&dir)
+            .output();
+        let ran = dir.join("glit-pwned").exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(!ran, "bash ran a command from the branch name");
+        match expanded {
+            Ok(expanded) if expanded.status.success() => {
+                assert_eq!(String::from_utf8(expanded.stdout).unwrap(), HOSTILE_BRANCH);
+            }
+            _ => eprintln!("skipping expansion check, bash 4.4 or later is not available"),
+        }
+    }
+
+    macro_rules! snapshot {
+        ($name:ident, $shell:expr, $prompt:expr) => {
+            #[test]
+            fn $name() {
+                insta::assert_snapshot!(script($shell, $prompt, ARGS).unwrap());
+            }
+        };
+    }
+
+    snapshot!(bash, Shell::Bash, Prompt::Left);
+    snapshot!(zsh, Shell::Zsh, Prompt::Left);
+    snapshot!(zsh_right, Shell::Zsh, Prompt::Right);
+    snapshot!(fish, Shell::Fish, Prompt::Left);
+    snapshot!(fish_right, Shell::Fish, Prompt::Right);
+    snapshot!(powershell, Shell::PowerShell, Prompt::Left);
+    snapshot!(elvish, Shell::Elvish, Prompt::Left);
+    snapshot!(elvish_right, Shell::Elvish, Prompt::Right);
+    snapshot!(nushell, Shell::Nushell, Prompt::Left);
+    snapshot!(nushell_right, Shell::Nushell, Prompt::Right);
+}
@@ -1,8 +1,10 @@
 //! Interpreter which transforms expressions into the desired output
 
-use crate::ast::{self, CompleteStyle, Delimiter, Expression, Name, Tree};
+use crate::ast::{self, Comparison, CompleteStyle, Delimiter, Expression, Filter, Name, Tree};
 use crate::color::*;
-use crate::git::Stats;
+pub use crate::color::{ColorChoice, ColorDepth, Escapes};
+use crate::git::{Operation, Stats};
+use crate::text::{self, Segment};
 
 use std::{fmt, io};
 
@@ -26,11 +28,15 @@ type Result<T = bool> = std::result::Result<T, InterpreterErr>;
 pub struct Interpreter {
     stats: Stats,
     allow_color: bool,
-    bash_prompt: bool,
+    escapes: Escapes,
+    color_depth: ColorDepth,
     command_queue: Vec<WriteCommand>,
+    /// Whether the output is captured for a filter, see `capture_tree`
+    capturing: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
 enum WriteCommand {
     WriteContext(CompleteStyle),
     WriteStr(&'static str),
@@ -39,16 +45,24 @@ enum WriteCommand {
 }
 
 impl Interpreter {
-    /// Create a new Interpreter with the given stats
-    pub fn new(stats: Stats, allow_color: bool, bash_prompt: bool) -> Interpreter {
+    /// Create a new Interpreter with the given stats, escaping its output for the prompt of a shell
+    pub fn new(stats: Stats, allow_color: bool, escapes: Escapes) -> Interpreter {
         Interpreter {
             stats,
             allow_color,
-            bash_prompt,
+            escapes,
+            color_depth: ColorDepth::default(),
             command_queue: Vec::with_capacity(32),
+            capturing: false,
         }
     }
 
+    /// Approximate RGB colors with the colors available at a lower color depth
+    pub fn with_color_depth(mut self, color_depth: ColorDepth) -> Interpreter {
+        self.color_depth = color_depth;
+        self
+    }
+
     fn drain_queue(&mut self, i: usize) {
         self.command_queue.truncate(self.command_queue.len() - i);
     }
@@ -78,19 +92,11 @@ impl Interpreter {
     /// Evaluate an expression tree and return the resulting formatted `String`
     pub fn evaluate<W: io::Write>(&mut self, exps: &Tree, w: &mut W) -> Result<()> {
         if self.allow_color {
-            self.queue_str(if self.bash_prompt {
-                "\u{01}\x1B[0m\u{02}"
-            } else {
-                "\x1B[0m"
-            });
+            self.queue_str(self.escapes.reset());
         }
 
-        if self.interpret_tree(w, &exps, CompleteStyle::default())? && self.allow_color {
-            if self.bash_prompt {
-                write!(w, "\u{01}\x1B[0m\u{02}")?;
-            } else {
-                write!(w, "\x1B[0m")?;
-            }
+        if self.interpret_tree(w, exps, CompleteStyle::default())? && self.allow_color {
+            write!(w, "{}", self.escapes.reset())?;
         }
 
         self.command_queue.clear();
@@ -104,7 +110,7 @@ impl Interpreter {
             use WriteCommand::*;
             match command {
                 WriteString(s) => write!(w, "{}", s)?,
-                WriteContext(c) => c.write_to(w, self.bash_prompt)?,
+                WriteContext(c) => c.write_to(w, self.escapes, self.color_depth)?,
                 WriteStr(s) => write!(w, "{}", s)?,
             }
         }
@@ -135,7 +141,7 @@ impl Interpreter {
                     // Clear separators between previous expression and the current
                     // one which was not written, to prevent accumulating separators
                     // between elements which were not supposed to have them
-                    if self.interpret(w, &e, context)? {
+                    if self.interpret(w, e, context)? {
                         wrote = true;
                     } else {
                         self.drain_queue(separator_count);
@@ -158,15 +164,145 @@ impl Interpreter {
 
         match exp {
             Named { name, ref sub } => self.interpret_named(w, *name, sub, ctx),
+            Compare {
+                name,
+                op,
+                value,
+                ref sub,
+            } => self.interpret_compare(w, *name, *op, *value, sub, ctx),
             Group { d, ref sub } => self.interpret_group(w, *d, sub, ctx),
             Format { ref style, ref sub } => self.interpret_format(w, *style, sub, ctx),
+            Literal(ref literal) if self.capturing => {
+                self.write_queue(w)?;
+                write!(w, "{}{}{}", text::LITERAL_START, literal, text::LITERAL_END)?;
+                Ok(true)
+            }
             Literal(ref literal) => {
                 self.write_queue(w)?;
                 write!(w, "{}", literal)?;
                 Ok(true)
             }
             Separator(_) => unreachable!("Separator must be handled in tree interpreter"),
+            Definition { .. } => Ok(false),
+            Reference { ref sub, .. } => self.interpret_tree(w, sub, ctx),
+            Filter {
+                ref filter,
+                ref sub,
+            } => self.interpret_filter(w, filter, sub, ctx),
+            Conditional {
+                ref condition,
+                ref then,
+                ref otherwise,
+            } => {
+                if self.is_empty_tree(condition, ctx)? {
+                    self.interpret_tree(w, otherwise, ctx)
+                } else {
+                    self.interpret_tree(w, then, ctx)
+                }
+            }
+        }
+    }
+
+    /// Whether a tree would output anything, without writing it
+    fn is_empty_tree(&mut self, tree: &Tree, ctx: CompleteStyle) -> Result {
+        // The queue holds output which is pending for whatever is written next, so it must be
+        // left untouched by the condition
+        let queue = std::mem::take(&mut self.command_queue);
+        let wrote = self.interpret_tree(&mut io::sink(), tree, ctx);
+        self.command_queue = queue;
+        wrote.map(|wrote| !wrote)
+    }
+
+    /// Interpret a tree into a string, without escaping it for the prompt of a shell
+    ///
+    /// Strings are marked with `LITERAL_START` and `LITERAL_END`, since they are not escaped when
+    /// the captured output is written.  Returns `None` when the tree does not output anything.
+    fn capture_tree(&mut self, tree: &Tree, ctx: CompleteStyle) -> Result<Option<String>> {
+        // Output which is pending for whatever is written next is not part of the tree
+        let queue = std::mem::take(&mut self.command_queue);
+        let escapes = std::mem::take(&mut self.escapes);
+        let capturing = std::mem::replace(&mut self.capturing, true);
+        let mut output = Vec::with_capacity(32);
+        let wrote = self.interpret_tree(&mut output, tree, ctx);
+        self.command_queue = queue;
+        self.escapes = escapes;
+        self.capturing = capturing;
+
+        if wrote? {
+            Ok(Some(String::from_utf8_lossy(&output).into_owned()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Write captured output, escaping its text and escape sequences for the prompt of a shell
+    ///
+    /// Text from strings is written as it is, so the shell can expand its prompt sequences.
+    fn write_captured<W: io::Write>(&mut self, w: &mut W, captured: &str) -> Result<()> {
+        self.write_queue(w)?;
+        // A filter inside of another keeps the markers for the outer filter
+        if self.capturing {
+            write!(w, "{}", captured)?;
+            return Ok(());
+        }
+
+        // Filters may move the text of a string, but never remove its markers
+        let mut literal = 0usize;
+        for segment in text::segments(captured) {
+            match segment {
+                Segment::Escape(text::LITERAL_START) => literal += 1,
+                Segment::Escape(text::LITERAL_END) => literal = literal.saturating_sub(1),
+                Segment::Text(text) if literal > 0 => write!(w, "{}", text)?,
+                Segment::Escape(sequence) => write!(
+                    w,
+                    "{}{}{}",
+                    self.escapes.start(),
+                    sequence,
+                    self.escapes.end()
+                )?,
+                Segment::Text(text) => write!(w, "{}", self.escapes.escape(text))?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Output the text of a sub-tree transformed by a filter
+    fn interpret_filter<W: io::Write>(
+        &mut self,
+        w: &mut W,
+        filter: &Filter,
+        sub: &Tree,
+        ctx: CompleteStyle,
+    ) -> Result {
+        let captured = match self.capture_tree(sub, ctx)? {
+            Some(captured) => captured,
+            None => return Ok(false),
+        };
+
+        let filtered = match filter {
+            Filter::Truncate { width, ellipsis } => {
+                text::truncate(&captured, *width as usize, ellipsis)
+            }
+            Filter::Pad { align, width } => text::pad(&captured, *width as usize, *align),
+            Filter::StripPrefix(prefix) => text::strip_prefix(&captured, prefix),
+            Filter::Capture(pattern) => text::capture(&captured, &pattern.0),
+            Filter::Replace {
+                pattern,
+                replacement,
+            } => text::replace(&captured, &pattern.0, replacement),
+            Filter::Upper => text::upper(&captured),
+            Filter::Lower => text::lower(&captured),
+            Filter::Title => text::title(&captured),
+            Filter::First(count) => text::first(&captured, *count as usize),
+        };
+
+        if !text::has_text(&filtered) {
+            return Ok(false);
         }
+
+        self.write_captured(w, &filtered)?;
+        Ok(true)
     }
 
     fn interpret_group<W: io::Write>(
@@ -176,11 +312,11 @@ impl Interpreter {
         sub: &Tree,
         style: CompleteStyle,
     ) -> Result {
-        if sub.0.len() > 0 {
+        if !sub.0.is_empty() {
             self.with_command(
                 w,
                 WriteCommand::WriteStr(d.left()),
-                |i, w| i.interpret_tree(w, &sub, style),
+                |i, w| i.interpret_tree(w, sub, style),
                 |_, w| write!(w, "{}", d.right()).map_err(|e| e.into()),
             )
         } else {
@@ -201,6 +337,18 @@ impl Interpreter {
             return Ok(false);
         }
 
+        self.write_prefix(w, sub, val, prefix, ctx)
+    }
+
+    /// Write a value along with its sub-tree, or its default prefix if the sub-tree is empty
+    fn write_prefix<W: io::Write, V1: fmt::Display, V2: fmt::Display>(
+        &mut self,
+        w: &mut W,
+        sub: &Tree,
+        val: V1,
+        prefix: V2,
+        ctx: CompleteStyle,
+    ) -> Result {
         self.write_queue(w)?;
 
         if sub.0.is_empty() {
@@ -242,20 +390,70 @@ impl Interpreter {
     ) -> Result {
         use ast::Name::*;
         match name {
-            Branch => self.optional_prefix(w, sub, self.stats.branch.clone(), "", ctx),
-            Remote => self.optional_prefix(w, sub, self.stats.remote.clone(), "", ctx),
-            Ahead => self.optional_prefix(w, sub, self.stats.ahead, "+", ctx),
-            Behind => self.optional_prefix(w, sub, self.stats.behind, "-", ctx),
-            Conflict => self.optional_prefix(w, sub, self.stats.conflicts, "U", ctx),
-            Added => self.optional_prefix(w, sub, self.stats.added_staged, "A", ctx),
-            Untracked => self.optional_prefix(w, sub, self.stats.untracked, "?", ctx),
-            Modified => self.optional_prefix(w, sub, self.stats.modified_staged, "M", ctx),
-            Unstaged => self.optional_prefix(w, sub, self.stats.modified, "M", ctx),
-            Deleted => self.optional_prefix(w, sub, self.stats.deleted, "D", ctx),
-            DeletedStaged => self.optional_prefix(w, sub, self.stats.deleted_staged, "D", ctx),
-            Renamed => self.optional_prefix(w, sub, self.stats.renamed, "R", ctx),
-            Stashed => self.optional_prefix(w, sub, self.stats.stashes, "H", ctx),
+            Branch => {
+                let branch = self.escapes.escape(&self.stats.branch).into_owned();
+                self.optional_prefix(w, sub, branch, "", ctx)
+            }
+            Remote => {
+                let remote = self.escapes.escape(&self.stats.remote).into_owned();
+                self.optional_prefix(w, sub, remote, "", ctx)
+            }
+            Operation => self.optional_prefix(w, sub, self.stats.operation, "", ctx),
+            RebaseOnto => self.optional_prefix(w, sub, self.stats.rebase_onto.clone(), "", ctx),
+            Incomplete => {
+                let incomplete = Flag(!self.stats.is_complete());
+                self.optional_prefix(w, sub, incomplete, "?", ctx)
+            }
             Quote => self.interpret_literal(w, sub, "'"),
+            _ => {
+                let (count, prefix) = self.count(name).expect("name is numeric");
+                self.optional_prefix(w, sub, count, prefix, ctx)
+            }
+        }
+    }
+
+    /// Value and default prefix of a numeric name
+    fn count(&self, name: Name) -> Option<(u32, &'static str)> {
+        use ast::Name::*;
+        let stats = &self.stats;
+        let count = match name {
+            Ahead => (stats.ahead.into(), "+"),
+            Behind => (stats.behind.into(), "-"),
+            Conflict => (stats.conflicts.into(), "U"),
+            Added => (stats.added_staged.into(), "A"),
+            Untracked => (stats.untracked.into(), "?"),
+            Modified => (stats.modified_staged.into(), "M"),
+            Unstaged => (stats.modified.into(), "M"),
+            Deleted => (stats.deleted.into(), "D"),
+            DeletedStaged => (stats.deleted_staged.into(), "D"),
+            Renamed => (stats.renamed.into(), "R"),
+            Stashed => (stats.stashes.into(), "H"),
+            RebaseStep => (stats.rebase_step.into(), ""),
+            RebaseTotal => (stats.rebase_total.into(), "/"),
+            Insertions => (stats.insertions, "+"),
+            InsertionsStaged => (stats.insertions_staged, "+"),
+            Deletions => (stats.deletions, "-"),
+            DeletionsStaged => (stats.deletions_staged, "-"),
+            Branch | Remote | Operation | RebaseOnto | Incomplete | Quote => return None,
+        };
+        Some(count)
+    }
+
+    /// Output a numeric name, even when it is zero, if its value satisfies the comparison
+    fn interpret_compare<W: io::Write>(
+        &mut self,
+        w: &mut W,
+        name: Name,
+        op: Comparison,
+        value: u32,
+        sub: &Tree,
+        ctx: CompleteStyle,
+    ) -> Result {
+        let (count, prefix) = self.count(name).expect("compared names are numeric");
+        if op.compare(count, value) {
+            self.write_prefix(w, sub, count, prefix, ctx)
+        } else {
+            Ok(false)
         }
     }
 
@@ -269,18 +467,31 @@ impl Interpreter {
         let prev = context;
         context += style;
 
+        if !self.allow_color {
+            return self.interpret_tree(w, sub, context);
+        }
+
         self.with_command(
             w,
             WriteCommand::WriteContext(context),
             |i, w| i.interpret_tree(w, sub, context),
             |i, w| {
-                prev.write_difference(w, &context, i.bash_prompt)
+                prev.write_difference(w, &context, i.escapes, i.color_depth)
                     .map_err(|e| e.into())
             },
         )
     }
 }
 
+/// A value which has no text of its own, so only its prefix is shown when it is set
+struct Flag(bool);
+
+impl fmt::Display for Flag {
+    fn fmt(&self, _: &mut fmt::Formatter) -> fmt::Result {
+        Ok(())
+    }
+}
+
 /// Trait which determines what is empty in the eyes of the Interpreter
 ///
 /// The interpreter simply ignores the macros which correspond to "empty" values.
@@ -294,6 +505,12 @@ impl Empty for u16 {
     }
 }
 
+impl Empty for u32 {
+    fn is_empty(&self) -> bool {
+        *self == 0
+    }
+}
+
 impl Empty for str {
     fn is_empty(&self) -> bool {
         self.is_empty()
@@ -306,6 +523,18 @@ impl Empty for String {
     }
 }
 
+impl Empty for Operation {
+    fn is_empty(&self) -> bool {
+        *self == Operation::None
+    }
+}
+
+impl Empty for Flag {
+    fn is_empty(&self) -> bool {
+        !self.0
+    }
+}
+
 impl<T> Empty for Vec<T> {
     fn is_empty(&self) -> bool {
         self.is_empty()
@@ -319,8 +548,8 @@ mod test {
     use crate::git::Stats;
     use ast;
     use ast::{Delimiter, Expression, Name, Tree};
-    use proptest::arbitrary::any;
     use proptest::collection::vec;
+    use proptest::strategy::Just;
     use proptest::strategy::Strategy;
 
     proptest! {
@@ -333,7 +562,7 @@ mod test {
 
             let stats: Stats = Default::default();
 
-            let mut interpreter = Interpreter::new(stats, false, false);
+            let mut interpreter = Interpreter::new(stats, false, Escapes::None);
 
             let exp = Expression::Named { name, sub: Tree::new() };
 
@@ -363,7 +592,7 @@ mod test {
                 sub: Tree(vec![interior]),
             };
 
-            let mut interpreter = Interpreter::new(stats, false, false);
+            let mut interpreter = Interpreter::new(stats, false, Escapes::None);
 
             let mut output = Vec::with_capacity(32);
             match interpreter.evaluate(&Tree(vec![exp.clone()]), &mut output) {
@@ -389,7 +618,12 @@ mod test {
                 .prop_filter("Quote is never empty".to_owned(),
                              |n| *n != Name::Quote),
             style in vec(ast::arb_style(), 1..10),
-            bash_prompt in any::<bool>()
+            escapes in prop_oneof![
+                Just(Escapes::None),
+                Just(Escapes::Bash),
+                Just(Escapes::Zsh),
+                Just(Escapes::Tcsh)
+            ]
         ) {
             let stats = Stats::default();
             let interior = Expression::Named { name, sub: Tree::new(), };
@@ -398,7 +632,7 @@ mod test {
                 sub: Tree(vec![interior]),
             };
 
-            let mut interpreter = Interpreter::new(stats, true, bash_prompt);
+            let mut interpreter = Interpreter::new(stats, true, escapes);
             let mut output = Vec::with_capacity(32);
             match interpreter.evaluate(&Tree(vec![exp.clone()]), &mut output) {
                 Ok(()) => {
@@ -417,4 +651,354 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn operation_replaces_prefix() {
+        let stats = Stats {
+            operation: Operation::RebaseInteractive,
+            ..Default::default()
+        };
+        let tree = crate::parser::parse("o('on ')").unwrap();
+
+        let mut interpreter = Interpreter::new(stats, false, Escapes::None);
+        let mut output = Vec::with_capacity(32);
+        interpreter.evaluate(&tree, &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "on REBASE-i");
+    }
+
+    #[test]
+    fn rebase_progress() {
+        let stats = Stats {
+            operation: Operation::RebaseInteractive,
+            rebase_step: 3,
+            rebase_total: 7,
+            rebase_onto: "abc123ef".to_string(),
+            ..Default::default()
+        };
+        let tree = crate::parser::parse("[o nN O('onto ')]").unwrap();
+
+        let mut interpreter = Interpreter::new(stats, false, Escapes::None);
+        let mut output = Vec::with_capacity(32);
+        interpreter.evaluate(&tree, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "[REBASE-i 3/7 onto abc123ef]"
+        );
+    }
+
+    #[test]
+    fn incomplete_marker() {
+        use crate::git::Request;
+
+        let tree = crate::parser::parse("b !").unwrap();
+        let complete = Stats {
+            branch: "master".to_string(),
+            ..Default::default()
+        };
+        let incomplete = Stats {
+            missing: Request {
+                untracked: true,
+                ..Default::default()
+            },
+            ..complete.clone()
+        };
+
+        let mut output = Vec::with_capacity(32);
+        Interpreter::new(complete, false, Escapes::None)
+            .evaluate(&tree, &mut output)
+            .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "master");
+
+        let mut output = Vec::with_capacity(32);
+        Interpreter::new(incomplete, false, Escapes::None)
+            .evaluate(&tree, &mut output)
+            .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "master ?");
+    }
+
+    #[test]
+    fn conditional() {
+        let tree = crate::parser::parse("b ?(u, 'CONFLICT', 'ok') ?(+, 'ahead'), h").unwrap();
+        let stats = Stats {
+            branch: "master".to_string(),
+            ..Default::default()
+        };
+
+        let mut output = Vec::with_capacity(32);
+        Interpreter::new(stats.clone(), false, Escapes::None)
+            .evaluate(&tree, &mut output)
+            .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "master ok");
+
+        let conflict = Stats {
+            conflicts: 1,
+            ahead: 1,
+            ..stats
+        };
+        let mut output = Vec::with_capacity(32);
+        Interpreter::new(conflict, false, Escapes::None)
+            .evaluate(&tree, &mut output)
+            .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "master CONFLICT ahead");
+    }
+
+    #[test]
+    fn comparison() {
+        let tree = crate::parser::parse("?(->10, 'far behind', -) +==0('even') m<3").unwrap();
+        let render = |stats: Stats| {
+            let mut output = Vec::with_capacity(32);
+            Interpreter::new(stats, false, Escapes::None)
+                .evaluate(&tree, &mut output)
+                .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(render(Stats::default()), "even0 M0");
+        let behind = Stats {
+            behind: 11,
+            ahead: 1,
+            modified: 2,
+            ..Default::default()
+        };
+        assert_eq!(render(behind), "far behind M2");
+        let behind = Stats {
+            behind: 10,
+            modified: 3,
+            ..Default::default()
+        };
+        assert_eq!(render(behind), "-10 even0");
+    }
+
+    #[test]
+    fn shell_escapes() {
+        let tree = crate::parser::parse("#r(b)'%~'").unwrap();
+        let stats = Stats {
+            branch: "100%!".to_string(),
+            ..Default::default()
+        };
+        let render = |escapes| {
+            let mut output = Vec::with_capacity(32);
+            Interpreter::new(stats.clone(), true, escapes)
+                .evaluate(&tree, &mut output)
+                .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(
+            render(Escapes::None),
+            "\x1B[0m\x1B[31m100%!\x1B[0m\x1B[0m%~\x1B[0m"
+        );
+        assert_eq!(
+            render(Escapes::Bash),
+            "\u{01}\x1B[0m\u{02}\u{01}\x1B[31m\u{02}100%!\u{01}\x1B[0m\x1B[0m\u{02}%~\u{01}\x1B[0m\u{02}"
+        );
+        assert_eq!(
+            render(Escapes::Zsh),
+            "%{\x1B[0m%}%{\x1B[31m%}100%%!%{\x1B[0m\x1B[0m%}%~%{\x1B[0m%}"
+        );
+        assert_eq!(
+            render(Escapes::Tcsh),
+            "%{\x1B[0m%}%{\x1B[31m%}100%%\\!%{\x1B[0m\x1B[0m%}%~%{\x1B[0m%}"
+        );
+    }
+
+    #[test]
+    fn color_depth() {
+        let tree = crate::parser::parse("#[255,135,0]{0,0,0}*('x')").unwrap();
+        let render = |depth| {
+            let mut output = Vec::with_capacity(32);
+            Interpreter::new(Stats::default(), true, Escapes::None)
+                .with_color_depth(depth)
+                .evaluate(&tree, &mut output)
+                .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        let styled = |style: &str| format!("\x1B[0m{}x\x1B[0m\x1B[0m\x1B[0m", style);
+        assert_eq!(
+            render(ColorDepth::TrueColor),
+            styled("\x1B[38;2;255;135;0m\x1B[48;2;0;0;0m\x1B[1m")
+        );
+        assert_eq!(
+            render(ColorDepth::Ansi256),
+            styled("\x1B[38;5;208m\x1B[48;5;16m\x1B[1m")
+        );
+        assert_eq!(
+            render(ColorDepth::Ansi16),
+            styled("\x1B[33m\x1B[40m\x1B[1m")
+        );
+        assert_eq!(render(ColorDepth::None), styled("\x1B[1m"));
+    }
+
+    #[test]
+    fn text_styles() {
+        let tree = crate::parser::parse("#x('a'#d=('b')'c')").unwrap();
+        let mut output = Vec::with_capacity(32);
+        Interpreter::new(Stats::default(), true, Escapes::None)
+            .evaluate(&tree, &mut output)
+            .unwrap();
+
+        // Dim and double underline can only be removed by resetting every style
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            concat!(
+                "\x1B[0m\x1B[9ma\x1B[2m\x1B[9m\x1B[21mb",
+                "\x1B[0m\x1B[9mc\x1B[0m\x1B[0m\x1B[0m"
+            )
+        );
+    }
+
+    #[test]
+    fn truncate() {
+        let stats = Stats {
+            branch: "feature/%glitter".to_string(),
+            ahead: 1,
+            ..Default::default()
+        };
+        let render = |format, escapes| {
+            let tree = crate::parser::parse(format).unwrap();
+            let mut output = Vec::with_capacity(32);
+            Interpreter::new(stats.clone(), true, escapes)
+                .evaluate(&tree, &mut output)
+                .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(
+            render("[%.20(b) +]", Escapes::None),
+            "\x1B[0m[feature/%glitter +1]\x1B[0m"
+        );
+        assert_eq!(
+            render("[%.9(b) +]", Escapes::None),
+            "\x1B[0m[feature/… +1]\x1B[0m"
+        );
+        assert_eq!(render("[%.0(b) +]", Escapes::None), "\x1B[0m[+1]\x1B[0m");
+        assert_eq!(
+            render("#g(%.9'%'(#*(b)))", Escapes::Zsh),
+            concat!(
+                "%{\x1B[0m%}%{\x1B[32m%}%{\x1B[32m\x1B[1m%}feature/%%",
+                "%{\x1B[0m\x1B[32m%}%{\x1B[0m\x1B[0m%}%{\x1B[0m%}"
+            )
+        );
+    }
+
+    #[test]
+    fn pad() {
+        let stats = Stats {
+            branch: "main".to_string(),
+            ..Default::default()
+        };
+        let tree = crate::parser::parse("#G(%>6(#k(b)))").unwrap();
+        let mut output = Vec::with_capacity(32);
+        Interpreter::new(stats, true, Escapes::Bash)
+            .evaluate(&tree, &mut output)
+            .unwrap();
+
+        // The padding has the style of the expression around the filter
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            concat!(
+                "\u{1}\x1B[0m\u{2}\u{1}\x1B[42m\u{2}  \u{1}\x1B[30m\x1B[42m\u{2}main",
+                "\u{1}\x1B[0m\x1B[42m\u{2}\u{1}\x1B[0m\x1B[0m\u{2}\u{1}\x1B[0m\u{2}"
+            )
+        );
+    }
+
+    #[test]
+    fn wide_pad() {
+        let render = |tree: &Tree| {
+            let mut output = Vec::new();
+            Interpreter::new(Stats::default(), false, Escapes::None)
+                .evaluate(tree, &mut output)
+                .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        let tree = crate::parser::parse(&format!("%>{}('x')", Filter::MAX_WIDTH)).unwrap();
+        let padded = render(&tree);
+        assert_eq!(padded.len(), Filter::MAX_WIDTH as usize);
+        assert!(padded.ends_with(" x"));
+
+        // Trees built without the parser can be wider than the parser allows
+        let tree = Tree(vec![Expression::Filter {
+            filter: Filter::Pad {
+                align: ast::Align::Center,
+                width: 70_000,
+            },
+            sub: Tree(vec![Expression::Literal("x".to_owned())]),
+        }]);
+        assert_eq!(render(&tree).len(), 70_000);
+    }
+
+    #[test]
+    fn text_filters() {
+        let stats = Stats {
+            remote: "origin/master".to_string(),
+            operation: Operation::CherryPick,
+            ..Default::default()
+        };
+        let tree = crate::parser::parse("%T(o) %:1(B) %U(%L('X')b)").unwrap();
+        let mut output = Vec::with_capacity(32);
+        Interpreter::new(stats, false, Escapes::None)
+            .evaluate(&tree, &mut output)
+            .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "Cherry-Picking o X");
+    }
+
+    #[test]
+    fn strings_in_filters() {
+        let render = |format, escapes| {
+            let stats = Stats {
+                branch: "50%-$(x)".to_string(),
+                ..Default::default()
+            };
+            let tree = crate::parser::parse(format).unwrap();
+            let mut output = Vec::with_capacity(32);
+            Interpreter::new(stats, false, escapes)
+                .evaluate(&tree, &mut output)
+                .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        // Prompt sequences in strings are left for the shell, but branch names are escaped
+        assert_eq!(render("%.20('%~ 'b)", Escapes::Zsh), "%~ 50%%-$(x)");
+        assert_eq!(render("%.20('\\w 'b)", Escapes::Bash), "\\w 50%-\\\\$(x)");
+    }
+
+    #[test]
+    fn rewrite_filters_keep_styles() {
+        let stats = Stats {
+            branch: "users/main".to_string(),
+            remote: "origin/main".to_string(),
+            ..Default::default()
+        };
+        let tree = crate::parser::parse("%#'users/'(#r(b)' '#g(B))").unwrap();
+        let mut output = Vec::with_capacity(32);
+        Interpreter::new(stats, true, Escapes::None)
+            .evaluate(&tree, &mut output)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            concat!(
+                "\x1B[0m\x1B[31mmain\x1B[0m\x1B[0m ",
+                "\x1B[32morigin/main\x1B[0m\x1B[0m\x1B[0m"
+            )
+        );
+    }
+
+    #[test]
+    fn disallow_color() {
+        let tree = crate::parser::parse("#r*(b)' '#g(B)").unwrap();
+        let stats = Stats {
+            branch: "master".to_string(),
+            remote: "origin/master".to_string(),
+            ..Default::default()
+        };
+
+        let mut output = Vec::with_capacity(32);
+        Interpreter::new(stats, false, Escapes::Bash)
+            .evaluate(&tree, &mut output)
+            .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "master origin/master");
+    }
 }
@@ -17,9 +17,14 @@ extern crate proptest;
 
 pub mod ast;
 mod color;
+pub mod config;
+#[cfg(unix)]
+pub mod daemon;
 pub mod git;
+pub mod init;
 pub mod interpreter;
 pub mod parser;
+mod text;
 
 pub use git::Stats;
 use std::fmt::{self, Display};
@@ -66,10 +71,10 @@ pub fn glitter<'a, W: io::Write>(
     stats: Stats,
     format: &'a str,
     allow_color: bool,
-    bash_prompt: bool,
+    escapes: interpreter::Escapes,
     w: &mut W,
 ) -> Result<(), Error<'a>> {
     let tree = parser::parse(format)?;
-    interpreter::Interpreter::new(stats, allow_color, bash_prompt).evaluate(&tree, w)?;
+    interpreter::Interpreter::new(stats, allow_color, escapes).evaluate(&tree, w)?;
     Ok(())
 }
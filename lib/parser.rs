@@ -2,19 +2,135 @@
 
 mod combinator;
 
-use crate::ast::{Color::*, CompleteStyle, Delimiter, Expression, Name, Separator, Style, Tree};
+use crate::ast::{
+    Align, Color::*, Comparison, CompleteStyle, Delimiter, Expression, Filter, Name, Pattern,
+    Separator, Style, Tree,
+};
+use regex::Regex;
+use std::cell::{Cell, RefCell};
 use std::fmt::{self, Display};
+use std::rc::Rc;
 use std::str;
+use std::sync::Arc;
 
 use combinator::{delimited_many0, map_err, map_fail};
 use nom::{error, IResult};
 
+/// Number of expressions which references may output in a format, beyond which it is rejected
+///
+/// Each reference outputs the whole tree of its definition again, so a few definitions which each
+/// refer to the one before twice would otherwise take exponential time to output.
+pub const MAX_EXPANDED: usize = 1 << 16;
+
+/// Definitions visible to the rest of a format while it is parsed
+///
+/// A definition is visible to everything after it in the format, including expressions outside
+/// of the sub-tree it is defined in, so every format is parsed with definitions of its own.  The
+/// definitions made by an expression which fails to parse are forgotten, since its input is
+/// parsed again as another kind of expression.
+#[derive(Default)]
+pub struct Definitions {
+    bound: RefCell<Bindings>,
+    /// Names whose definitions are being parsed
+    pending: RefCell<Vec<String>>,
+    /// Number of expressions output by the references parsed so far
+    expanded: Cell<usize>,
+}
+
+/// Names bound to trees, most recent first, so earlier bindings can be restored cheaply
+#[derive(Clone, Default)]
+struct Bindings(Option<Rc<Binding>>);
+
+struct Binding {
+    name: String,
+    sub: Arc<Tree>,
+    /// Number of expressions output by a reference to the name
+    size: usize,
+    next: Bindings,
+}
+
+impl Definitions {
+    /// Bind a name to a tree, replacing any earlier definition for the rest of the format
+    fn bind(&self, name: &str, sub: Arc<Tree>, size: usize) {
+        let mut bound = self.bound.borrow_mut();
+        let next = bound.clone();
+        *bound = Bindings(Some(Rc::new(Binding {
+            name: name.to_owned(),
+            sub,
+            size,
+            next,
+        })));
+    }
+
+    /// The tree most recently bound to a name, and the number of expressions it outputs
+    fn get(&self, name: &str) -> Result<(Arc<Tree>, usize), ParseErrorKind> {
+        let bound = self.bound.borrow();
+        let mut next = bound.0.as_deref();
+        while let Some(binding) = next {
+            if binding.name == name {
+                return Ok((Arc::clone(&binding.sub), binding.size));
+            }
+            next = binding.next.0.as_deref();
+        }
+
+        if self.pending.borrow().iter().any(|pending| pending == name) {
+            Err(ParseErrorKind::Recursive)
+        } else {
+            Err(ParseErrorKind::Undefined)
+        }
+    }
+
+    /// Wrap a parser so the definitions it made are forgotten when it fails
+    fn attempt<'d, 'a, O, F>(
+        &'d self,
+        parser: F,
+    ) -> impl Fn(&'a str) -> IResult<&'a str, O, ParseError<'a>> + 'd
+    where
+        F: Fn(&'a str) -> IResult<&'a str, O, ParseError<'a>> + 'd,
+    {
+        move |input| {
+            let saved = self.bound.borrow().clone();
+            let expanded = self.expanded.get();
+            let result = parser(input);
+            if result.is_err() {
+                *self.bound.borrow_mut() = saved;
+                self.expanded.set(expanded);
+            }
+            result
+        }
+    }
+}
+
+/// Number of expressions in a tree, not counting those output by references
+fn len(tree: &Tree) -> usize {
+    use Expression::*;
+
+    tree.0.iter().fold(0, |total, exp| {
+        let sub = match exp {
+            Named { sub, .. }
+            | Compare { sub, .. }
+            | Group { sub, .. }
+            | Format { sub, .. }
+            | Filter { sub, .. } => len(sub),
+            Conditional {
+                condition,
+                then,
+                otherwise,
+            } => len(condition)
+                .saturating_add(len(then))
+                .saturating_add(len(otherwise)),
+            Definition { .. } | Reference { .. } | Literal(_) | Separator(_) => 0,
+        };
+        total.saturating_add(1).saturating_add(sub)
+    })
+}
+
 /// Parse a format
 pub fn parse<'a>(input: &'a str) -> Result<Tree, ParseError<'a>> {
     use nom::combinator::all_consuming;
     use nom::Err;
 
-    all_consuming(expression_tree)(input.as_ref())
+    all_consuming(expression_tree)(input)
         .map(|(_, tree)| tree)
         .map_err(|e| match e {
             Err::Error(e) => e,
@@ -23,35 +139,47 @@ pub fn parse<'a>(input: &'a str) -> Result<Tree, ParseError<'a>> {
         })
 }
 
-pub fn expression_tree<'a>(input: &'a str) -> IResult<&str, Tree, ParseError<'a>> {
+/// Parse a sequence of expressions, with definitions of their own
+pub fn expression_tree<'a>(input: &'a str) -> IResult<&'a str, Tree, ParseError<'a>> {
     use nom::combinator::map;
     use nom::multi::many0;
 
-    map(many0(expression), Tree)(input)
+    let defs = Definitions::default();
+    let (rest, tree) = map(many0(|i| expression(&defs, i)), Tree)(input)?;
+    Ok((rest, tree))
 }
 
 /// Parse a single expression, expanding nested expressions
-pub fn expression<'a>(input: &'a str) -> IResult<&str, Expression, ParseError<'a>> {
+pub fn expression<'a>(
+    defs: &Definitions,
+    input: &'a str,
+) -> IResult<&'a str, Expression, ParseError<'a>> {
     use nom::branch::alt;
     use nom::error::context;
 
     alt((
-        context("group", group_expression),
+        context("group", defs.attempt(|i| group_expression(defs, i))),
         context("string", literal_expression),
-        context("format", format_expression),
+        context("format", defs.attempt(|i| format_expression(defs, i))),
+        context(
+            "conditional",
+            defs.attempt(|i| conditional_expression(defs, i)),
+        ),
+        context("definition", defs.attempt(|i| binding_expression(defs, i))),
+        context("filter", defs.attempt(|i| filter_expression(defs, i))),
         separator_expression,
-        named_expression,
+        defs.attempt(|i| named_expression(defs, i)),
     ))(input)
 }
 
-fn sub_tree<'a>(input: &'a str) -> IResult<&str, Tree, ParseError<'a>> {
+fn sub_tree<'a>(defs: &Definitions, input: &'a str) -> IResult<&'a str, Tree, ParseError<'a>> {
     use nom::character::complete::char;
     use nom::combinator::map;
     // use nom::sequence::delimited;
 
     let items = delimited_many0(
         char('('),
-        expression,
+        |i| expression(defs, i),
         map_err(char(')'), |_, e| {
             ParseError::missing_delimiter(input, e, ')')
         }),
@@ -60,7 +188,10 @@ fn sub_tree<'a>(input: &'a str) -> IResult<&str, Tree, ParseError<'a>> {
     map(items, Tree)(input)
 }
 
-pub fn named_expression<'a>(input: &'a str) -> IResult<&str, Expression, ParseError<'a>> {
+pub fn named_expression<'a>(
+    defs: &Definitions,
+    input: &'a str,
+) -> IResult<&'a str, Expression, ParseError<'a>> {
     use nom::branch::alt;
     use nom::bytes::complete::tag;
     use nom::character::complete::char;
@@ -69,8 +200,10 @@ pub fn named_expression<'a>(input: &'a str) -> IResult<&str, Expression, ParseEr
     // sub-parsers for each type of name, this defines what
     // literal values are translated to what names; must match the
     // fmt::Display implementation
+    //
+    // `alt` accepts at most 21 parsers, so names are split into groups
     use Name::*;
-    let name = alt((
+    let basic = alt((
         map(char('h'), |_| Stashed),
         map(char('b'), |_| Branch),
         map(char('B'), |_| Remote),
@@ -84,40 +217,106 @@ pub fn named_expression<'a>(input: &'a str) -> IResult<&str, Expression, ParseEr
         map(char('d'), |_| Deleted),
         map(char('D'), |_| DeletedStaged),
         map(char('R'), |_| Renamed),
-        map(tag("\\\'"), |_| Quote),
     ));
+    let extended = alt((
+        map(char('o'), |_| Operation),
+        map(char('n'), |_| RebaseStep),
+        map(char('N'), |_| RebaseTotal),
+        map(char('O'), |_| RebaseOnto),
+        map(char('i'), |_| Insertions),
+        map(char('I'), |_| InsertionsStaged),
+        map(char('x'), |_| Deletions),
+        map(char('X'), |_| DeletionsStaged),
+        map(char('!'), |_| Incomplete),
+    ));
+    let name = alt((basic, extended, map(tag("\\\'"), |_| Quote)));
 
     let name = map_err(name, ParseError::missing_name);
 
     // Optional argument sub_tree
-    let prefix = map_err(opt(sub_tree), |_, e| {
+    let prefix = map_err(opt(defs.attempt(|i| sub_tree(defs, i))), |_, e| {
         error::ParseError::add_context(input, "expression", e)
     });
 
-    // First, read name from input and then read the arguments.
-    name(input).and_then(|(input, name)| {
-        map(prefix, |args| Expression::Named {
-            name,
-            sub: args.unwrap_or_else(|| Tree::new()),
-        })(input)
-    })
+    // First, read name from input, then an optional comparison, and then read the arguments.
+    let (rest, name) = name(input)?;
+    let (rest, compare) = opt(comparison)(rest)?;
+    let (rest, args) = prefix(rest)?;
+    let sub = args.unwrap_or_default();
+
+    match compare {
+        None => Ok((rest, Expression::Named { name, sub })),
+        Some(_) if !name.is_numeric() => Err(nom::Err::Failure(ParseError::from_kind(
+            input,
+            ParseErrorKind::NotNumeric,
+        ))),
+        Some((op, value)) => Ok((
+            rest,
+            Expression::Compare {
+                name,
+                op,
+                value,
+                sub,
+            },
+        )),
+    }
+}
+
+/// Parse a comparison to a constant like `>10` which follows a name
+///
+/// The operator must be followed by a number, otherwise `<` is left to be parsed as a group.
+pub fn comparison<'a>(input: &'a str) -> IResult<&'a str, (Comparison, u32), ParseError<'a>> {
+    use nom::branch::alt;
+    use nom::bytes::complete::tag;
+    use nom::character::complete::char;
+    use nom::combinator::map;
+
+    let op = alt((
+        map(tag("=="), |_| Comparison::Equal),
+        map(char('>'), |_| Comparison::Greater),
+        map(char('<'), |_| Comparison::Less),
+    ));
+
+    let (rest, op) = op(input)?;
+    let (rest, value) = number(rest)?;
+    Ok((rest, (op, value)))
+}
+
+/// Parse a number, failing when it does not fit in a `u32`
+fn number<'a>(input: &'a str) -> IResult<&'a str, u32, ParseError<'a>> {
+    use nom::character::complete::digit1;
+
+    let (rest, digits) = digit1(input)?;
+    match digits.parse() {
+        Ok(value) => Ok((rest, value)),
+        Err(_) => Err(nom::Err::Failure(ParseError::from_kind(
+            digits,
+            ParseErrorKind::NumberTooLarge(u32::MAX),
+        ))),
+    }
 }
 
-fn u8_from_bytes<'a>(input: &'a str) -> u8 {
-    input
-        .parse()
-        .expect("attempted to parse a value that was not a number")
+/// Parse a number of terminal columns, failing when it is more than `Filter::MAX_WIDTH`
+fn width<'a>(input: &'a str) -> IResult<&'a str, u32, ParseError<'a>> {
+    let (rest, width) = map_fail(number, missing_number)(input)?;
+    if width > Filter::MAX_WIDTH {
+        return Err(nom::Err::Failure(ParseError::from_kind(
+            input,
+            ParseErrorKind::NumberTooLarge(Filter::MAX_WIDTH),
+        )));
+    }
+    Ok((rest, width))
 }
 
-fn digit<'a>(input: &'a str) -> IResult<&str, u8, ParseError<'a>> {
+fn digit<'a>(input: &'a str) -> IResult<&'a str, u8, ParseError<'a>> {
     use nom::bytes::complete::take_while1;
     use nom::character::is_digit;
-    use nom::combinator::map;
+    use nom::combinator::map_res;
 
-    map(take_while1(|c| is_digit(c as u8)), u8_from_bytes)(input)
+    map_res(take_while1(|c| is_digit(c as u8)), str::parse)(input)
 }
 
-fn u8_triple<'a>(input: &'a str) -> IResult<&str, (u8, u8, u8), ParseError<'a>> {
+fn u8_triple<'a>(input: &'a str) -> IResult<&'a str, (u8, u8, u8), ParseError<'a>> {
     use nom::character::complete::char;
     use nom::sequence::{terminated, tuple};
 
@@ -128,11 +327,56 @@ fn u8_triple<'a>(input: &'a str) -> IResult<&str, (u8, u8, u8), ParseError<'a>>
     ))(input)
 }
 
-fn style_token<'a>(input: &'a str) -> IResult<&str, Style, ParseError<'a>> {
+/// Parse a hex color like `#ffaf34`, or its shorthand `#fa3`
+fn hex_color<'a>(input: &'a str) -> IResult<&'a str, (u8, u8, u8), ParseError<'a>> {
+    use nom::bytes::complete::take_while;
+    use nom::character::complete::char;
+
+    let (rest, _) = char('#')(input)?;
+    let (rest, digits) = take_while(|c: char| c.is_ascii_alphanumeric())(rest)?;
+    // each digit of the shorthand is repeated, so `#fa3` is the same as `#ffaa33`
+    let channel = |i: usize, len: usize| {
+        let value = u8::from_str_radix(&digits[i * len..(i + 1) * len], 16).unwrap_or(0);
+        if len == 1 {
+            value * 17
+        } else {
+            value
+        }
+    };
+
+    match digits.len() {
+        len @ 3 | len @ 6 if digits.chars().all(|c| c.is_ascii_hexdigit()) => {
+            let len = len / 3;
+            Ok((rest, (channel(0, len), channel(1, len), channel(2, len))))
+        }
+        _ => Err(nom::Err::Failure(ParseError::from_kind(
+            input,
+            ParseErrorKind::InvalidHex,
+        ))),
+    }
+}
+
+/// Parse the index of a color in the 256 color table
+///
+/// Without any digits, the opening delimiter is left to be parsed as the start of a sub-tree.
+fn color_index<'a>(input: &'a str) -> IResult<&'a str, u8, ParseError<'a>> {
+    use nom::character::complete::digit1;
+
+    let (rest, digits) = digit1(input)?;
+    match digits.parse() {
+        Ok(index) => Ok((rest, index)),
+        Err(_) => Err(nom::Err::Failure(ParseError::from_kind(
+            digits,
+            ParseErrorKind::InvalidIndex,
+        ))),
+    }
+}
+
+fn style_token<'a>(input: &'a str) -> IResult<&'a str, Style, ParseError<'a>> {
     use nom::branch::alt;
     use nom::character::complete::char;
     use nom::combinator::{complete, map};
-    use nom::sequence::delimited;
+    use nom::sequence::{delimited, preceded};
 
     // create sub-parsers for each style type
     use Style::*;
@@ -145,34 +389,44 @@ fn style_token<'a>(input: &'a str) -> IResult<&str, Style, ParseError<'a>> {
     // sub-parsers for each type of style, this defines what
     // literals translate to what Style Tokens; must match the
     // fmt::Display implementation
-    let styles = alt((
+    let text_styles = alt((
         style!('~', Reset),
         style!('*', Bold),
         style!('_', Underline),
         style!('i', Italic),
-        style!('r', Fg(Red)),
-        style!('R', Bg(Red)),
-        style!('g', Fg(Green)),
-        style!('G', Bg(Green)),
-        style!('y', Fg(Yellow)),
-        style!('Y', Bg(Yellow)),
-        style!('b', Fg(Blue)),
-        style!('B', Bg(Blue)),
-        style!('m', Fg(Magenta)),
-        style!('M', Bg(Magenta)),
-        style!('c', Fg(Cyan)),
-        style!('C', Bg(Cyan)),
-        style!('w', Fg(White)),
-        style!('W', Bg(White)),
-        style!('k', Fg(Black)),
-        style!('K', Bg(Black)),
+        style!('d', Dim),
+        style!('f', Blink),
+        style!('v', Reverse),
+        style!('x', Strikethrough),
+        style!('=', DoubleUnderline),
+        style!('o', Overline),
     ));
+    let colors = || {
+        alt((
+            style!('r', Fg(Red)),
+            style!('R', Bg(Red)),
+            style!('g', Fg(Green)),
+            style!('G', Bg(Green)),
+            style!('y', Fg(Yellow)),
+            style!('Y', Bg(Yellow)),
+            style!('b', Fg(Blue)),
+            style!('B', Bg(Blue)),
+            style!('m', Fg(Magenta)),
+            style!('M', Bg(Magenta)),
+            style!('c', Fg(Cyan)),
+            style!('C', Bg(Cyan)),
+            style!('w', Fg(White)),
+            style!('W', Bg(White)),
+            style!('k', Fg(Black)),
+            style!('K', Bg(Black)),
+        ))
+    };
 
     // more complicated sub-parsers for RGB/Indexed Color styles
     let fg_rgb = map(
         complete(delimited(
             char('['),
-            map_fail(u8_triple, ParseError::invalid_rgb),
+            alt((hex_color, map_fail(u8_triple, ParseError::invalid_rgb))),
             map_fail(char(']'), ParseError::char_to_delimiter),
         )),
         |(r, g, b)| Fg(RGB(r, g, b)),
@@ -180,16 +434,50 @@ fn style_token<'a>(input: &'a str) -> IResult<&str, Style, ParseError<'a>> {
     let bg_rgb = map(
         complete(delimited(
             char('{'),
-            map_fail(u8_triple, ParseError::invalid_rgb),
+            alt((hex_color, map_fail(u8_triple, ParseError::invalid_rgb))),
             map_fail(char('}'), ParseError::char_to_delimiter),
         )),
         |(r, g, b)| Bg(RGB(r, g, b)),
     );
 
-    alt((fg_rgb, bg_rgb, map_err(styles, ParseError::missing_style)))(input)
+    // bright colors are the basic colors preceded by `+`
+    let bright = map(preceded(char('+'), colors()), |style| match style {
+        Fg(color) => Fg(color.bright()),
+        Bg(color) => Bg(color.bright()),
+        style => style,
+    });
+    let styles = alt((text_styles, colors(), bright));
+
+    let fg_indexed = map(
+        complete(delimited(
+            char('('),
+            color_index,
+            map_fail(char(')'), ParseError::char_to_delimiter),
+        )),
+        |i| Fg(Indexed(i)),
+    );
+    let bg_indexed = map(
+        complete(delimited(
+            char('<'),
+            color_index,
+            map_fail(char('>'), ParseError::char_to_delimiter),
+        )),
+        |i| Bg(Indexed(i)),
+    );
+
+    alt((
+        fg_rgb,
+        bg_rgb,
+        fg_indexed,
+        bg_indexed,
+        map_err(styles, ParseError::missing_style),
+    ))(input)
 }
 
-pub fn format_expression<'a>(input: &'a str) -> IResult<&str, Expression, ParseError<'a>> {
+pub fn format_expression<'a>(
+    defs: &Definitions,
+    input: &'a str,
+) -> IResult<&'a str, Expression, ParseError<'a>> {
     use nom::bytes::complete::tag;
     use nom::combinator::{cut, map};
     use nom::multi::fold_many1;
@@ -215,7 +503,7 @@ pub fn format_expression<'a>(input: &'a str) -> IResult<&str, Expression, ParseE
         }),
     );
 
-    let arguments = cut(sub_tree);
+    let arguments = cut(|i| sub_tree(defs, i));
 
     style(input).and_then(|(input, style)| {
         map(arguments, |sub_tree| Expression::Format {
@@ -225,7 +513,10 @@ pub fn format_expression<'a>(input: &'a str) -> IResult<&str, Expression, ParseE
     })
 }
 
-pub fn group_expression<'a>(input: &'a str) -> IResult<&str, Expression, ParseError<'a>> {
+pub fn group_expression<'a>(
+    defs: &Definitions,
+    input: &'a str,
+) -> IResult<&'a str, Expression, ParseError<'a>> {
     use nom::branch::alt;
     use nom::bytes::complete::tag;
     use nom::character::complete::char;
@@ -236,7 +527,7 @@ pub fn group_expression<'a>(input: &'a str) -> IResult<&str, Expression, ParseEr
             map(
                 delimited_many0(
                     tag($l),
-                    expression,
+                    |i| expression(defs, i),
                     map_err(char($r), |_, e| ParseError::char_to_delimiter(input, e)),
                 ),
                 |sub| Expression::Group {
@@ -255,29 +546,323 @@ pub fn group_expression<'a>(input: &'a str) -> IResult<&str, Expression, ParseEr
     ))(input)
 }
 
-pub fn literal_expression<'a>(input: &'a str) -> IResult<&str, Expression, ParseError<'a>> {
-    use nom::bytes::complete::take_until;
-    use nom::character::complete::char;
+/// Parse a string between single or double quotes
+pub fn literal_expression<'a>(input: &'a str) -> IResult<&'a str, Expression, ParseError<'a>> {
     use nom::combinator::map;
-    use nom::sequence::delimited;
 
-    let contents = map(
-        map_fail(take_until("\'"), |i, mut e: ParseError<'a>| {
-            e.error = (i, UnclosedString);
-            e
-        }),
-        str::to_owned,
+    map(string, Expression::Literal)(input)
+}
+
+/// Parse the contents of a string between single or double quotes
+fn string<'a>(input: &'a str) -> IResult<&'a str, String, ParseError<'a>> {
+    use nom::branch::alt;
+
+    // Report the usual single quote when neither quote is found
+    match alt((single_quoted, double_quoted))(input) {
+        Err(nom::Err::Error(_)) => Err(nom::Err::Error(error::ParseError::from_char(input, '\''))),
+        result => result,
+    }
+}
+
+/// Parse a string between single quotes, which has no escapes
+///
+/// Every backslash is part of the string, so the prompt escapes of shells like `\w` are output
+/// unchanged.
+fn single_quoted<'a>(input: &'a str) -> IResult<&'a str, String, ParseError<'a>> {
+    use nom::character::complete::char;
+
+    let (rest, _) = char('\'')(input)?;
+    match rest.find('\'') {
+        Some(end) => Ok((&rest[end + 1..], rest[..end].to_owned())),
+        None => Err(nom::Err::Failure(ParseError::from_kind(
+            rest,
+            ParseErrorKind::UnclosedString('\''),
+        ))),
+    }
+}
+
+/// Parse a string between double quotes, which may contain the escapes `\n`, `\t`, `\u{...}`,
+/// `\"`, `\'` and `\\`
+fn double_quoted<'a>(input: &'a str) -> IResult<&'a str, String, ParseError<'a>> {
+    use nom::character::complete::char;
+
+    let (rest, _) = char('"')(input)?;
+    let mut contents = String::new();
+    let mut i = 0;
+    while let Some(c) = rest[i..].chars().next() {
+        match c {
+            '"' => return Ok((&rest[i + 1..], contents)),
+            '\\' => {
+                let (escaped, len) = escape(&rest[i..])?;
+                contents.push(escaped);
+                i += len;
+            }
+            c => {
+                contents.push(c);
+                i += c.len_utf8();
+            }
+        }
+    }
+
+    Err(nom::Err::Failure(ParseError::from_kind(
+        rest,
+        ParseErrorKind::UnclosedString('"'),
+    )))
+}
+
+/// Parse an escape at the start of the input, returning the escaped character and the length of
+/// the escape
+fn escape<'a>(input: &'a str) -> Result<(char, usize), nom::Err<ParseError<'a>>> {
+    let fail = |kind| nom::Err::Failure(ParseError::from_kind(input, kind));
+
+    let escaped = match input[1..].chars().next() {
+        Some('n') => '\n',
+        Some('t') => '\t',
+        Some('u') => {
+            let code = input[2..]
+                .strip_prefix('{')
+                .and_then(|code| code.split_once('}'))
+                .map(|(code, _)| code)
+                .ok_or_else(|| fail(ParseErrorKind::InvalidUnicode))?;
+            // `from_str_radix` also accepts a sign before the digits
+            let escaped = match code.len() {
+                1..=6 if code.chars().all(|c| c.is_ascii_hexdigit()) => {
+                    u32::from_str_radix(code, 16).ok().and_then(char::from_u32)
+                }
+                _ => None,
+            };
+            let escaped = escaped.ok_or_else(|| fail(ParseErrorKind::InvalidUnicode))?;
+            return Ok((escaped, code.len() + 4));
+        }
+        Some(c @ '"') | Some(c @ '\'') | Some(c @ '\\') => c,
+        _ => return Err(fail(ParseErrorKind::InvalidEscape)),
+    };
+
+    Ok((escaped, 2))
+}
+
+/// Parse a filter like `%.20(b)`, which transforms the text output by its sub-tree
+pub fn filter_expression<'a>(
+    defs: &Definitions,
+    input: &'a str,
+) -> IResult<&'a str, Expression, ParseError<'a>> {
+    use nom::character::complete::char;
+    use nom::combinator::cut;
+
+    let (rest, _) = char('%')(input)?;
+    let (rest, filter) = match filter(rest) {
+        Err(nom::Err::Error(_)) => Err(nom::Err::Failure(ParseError::from_kind(
+            rest,
+            ParseErrorKind::UnrecognizedFilter,
+        ))),
+        result => result,
+    }?;
+    let (rest, sub) = cut(|i| sub_tree(defs, i))(rest)?;
+
+    Ok((rest, Expression::Filter { filter, sub }))
+}
+
+fn filter<'a>(input: &'a str) -> IResult<&'a str, Filter, ParseError<'a>> {
+    use nom::branch::alt;
+    use nom::character::complete::char;
+    use nom::combinator::{map, opt};
+    use nom::sequence::{pair, preceded};
+
+    let truncate = map(
+        preceded(char('.'), pair(width, opt(string))),
+        |(width, ellipsis)| Filter::Truncate {
+            width,
+            ellipsis: ellipsis.unwrap_or_else(|| Filter::ELLIPSIS.to_owned()),
+        },
     );
 
-    use ParseErrorKind::UnclosedString;
+    let align = alt((
+        map(char('<'), |_| Align::Left),
+        map(char('>'), |_| Align::Right),
+        map(char('^'), |_| Align::Center),
+    ));
+    let pad = map(pair(align, width), |(align, width)| Filter::Pad {
+        align,
+        width,
+    });
 
-    map(
-        delimited(char('\''), contents, char('\'')),
-        Expression::Literal,
-    )(input)
+    let strip_prefix = map(
+        preceded(char('#'), map_fail(string, |_, e| e)),
+        Filter::StripPrefix,
+    );
+    let rewrite = map(
+        preceded(
+            char('/'),
+            pair(
+                pattern,
+                opt(preceded(char('/'), map_fail(string, |_, e| e))),
+            ),
+        ),
+        |(pattern, replacement)| match replacement {
+            Some(replacement) => Filter::Replace {
+                pattern,
+                replacement,
+            },
+            None => Filter::Capture(pattern),
+        },
+    );
+
+    let case = alt((
+        map(char('U'), |_| Filter::Upper),
+        map(char('L'), |_| Filter::Lower),
+        map(char('T'), |_| Filter::Title),
+    ));
+    let first = map(preceded(char(':'), width), Filter::First);
+
+    alt((truncate, pad, strip_prefix, rewrite, case, first))(input)
+}
+
+/// Parse a string which contains a regular expression, failing when the expression is invalid
+fn pattern<'a>(input: &'a str) -> IResult<&'a str, Pattern, ParseError<'a>> {
+    let (rest, pattern) = map_fail(string, |_, e| e)(input)?;
+    match Regex::new(&pattern) {
+        Ok(regex) => Ok((rest, Pattern(regex))),
+        Err(e) => Err(nom::Err::Failure(ParseError::from_kind(
+            input,
+            ParseErrorKind::InvalidRegex(regex_error(&e)),
+        ))),
+    }
 }
 
-pub fn separator_expression<'a>(input: &'a str) -> IResult<&str, Expression, ParseError<'a>> {
+/// Reason a regular expression is invalid, without the pattern which the error repeats
+fn regex_error(e: &regex::Error) -> String {
+    match e {
+        regex::Error::Syntax(message) => message
+            .lines()
+            .rev()
+            .find_map(|line| line.strip_prefix("error: "))
+            .unwrap_or(message)
+            .to_owned(),
+        e => e.to_string(),
+    }
+}
+
+/// Report a missing number, leaving other errors like a number which is too large unchanged
+fn missing_number<'a>(input: &'a str, e: ParseError<'a>) -> ParseError<'a> {
+    if let ParseErrorKind::Other(_) = e.error.1 {
+        ParseError::from_kind(input, ParseErrorKind::MissingNumber)
+    } else {
+        e
+    }
+}
+
+pub fn conditional_expression<'a>(
+    defs: &Definitions,
+    input: &'a str,
+) -> IResult<&'a str, Expression, ParseError<'a>> {
+    use nom::bytes::complete::tag;
+    use nom::character::complete::char;
+    use nom::combinator::opt;
+    use nom::sequence::preceded;
+
+    let (i, _) = tag("?(")(input)?;
+    let argument = |i| conditional_argument(defs, i);
+    let (i, condition) = argument(i)?;
+    let (i, _) = map_fail(char(','), |_, e| e)(i)?;
+    let (i, then) = argument(i)?;
+    let (i, otherwise) = opt(preceded(char(','), argument))(i)?;
+    let (i, _) = map_fail(char(')'), |_, e| e)(i)?;
+
+    Ok((
+        i,
+        Expression::Conditional {
+            condition,
+            then,
+            otherwise: otherwise.unwrap_or_default(),
+        },
+    ))
+}
+
+/// Parse the expressions of a conditional argument, up to the next comma or closing parenthesis
+fn conditional_argument<'a>(
+    defs: &Definitions,
+    input: &'a str,
+) -> IResult<&'a str, Tree, ParseError<'a>> {
+    use nom::combinator::{map, verify};
+    use nom::multi::many0;
+    use nom::Err;
+
+    let not_comma = |e: &Expression| *e != Expression::Separator(Separator::Comma);
+    let (rest, tree) = map(many0(verify(|i| expression(defs, i), not_comma)), Tree)(input)?;
+
+    match rest.chars().next() {
+        Some(',') | Some(')') => Ok((rest, tree)),
+        None => Err(Err::Failure(ParseError::missing_delimiter(
+            rest,
+            error::ParseError::from_char(rest, ')'),
+            ')',
+        ))),
+        // Report the error from the expression which ended the argument
+        Some(_) => match expression(defs, rest) {
+            Err(Err::Error(e)) => Err(Err::Failure(e)),
+            Err(e) => Err(e),
+            Ok(_) => unreachable!("argument ended before a valid expression"),
+        },
+    }
+}
+
+/// Parse a definition `&name=(...)`, or a reference `&name` to an earlier definition
+///
+/// The name may be written in braces, like `&{name}`, to end it before letters, digits or
+/// underscores which follow.
+pub fn binding_expression<'a>(
+    defs: &Definitions,
+    input: &'a str,
+) -> IResult<&'a str, Expression, ParseError<'a>> {
+    use nom::branch::alt;
+    use nom::bytes::complete::take_while1;
+    use nom::character::complete::char;
+    use nom::combinator::opt;
+    use nom::sequence::delimited;
+    use nom::Err;
+
+    let identifier = take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_');
+    let name = alt((delimited(char('{'), &identifier, char('}')), &identifier));
+
+    let (i, _) = char('&')(input)?;
+    let (i, name) = map_fail(name, |i, _: ParseError<'a>| {
+        ParseError::from_kind(i, ParseErrorKind::MissingIdentifier)
+    })(i)?;
+    let (i, define) = opt(char('='))(i)?;
+
+    if define.is_some() {
+        // The name is pending until its tree is parsed, so references to it are recursive
+        let expanded = defs.expanded.get();
+        defs.pending.borrow_mut().push(name.to_owned());
+        let sub = map_fail(|i| sub_tree(defs, i), |_, e| e)(i);
+        defs.pending.borrow_mut().pop();
+        let (i, sub) = sub?;
+
+        // A reference outputs the tree, along with everything its own references output
+        let size = len(&sub).saturating_add(defs.expanded.get().saturating_sub(expanded));
+        let sub = Arc::new(sub);
+        defs.bind(name, Arc::clone(&sub), size);
+        let name = name.to_owned();
+        Ok((i, Expression::Definition { name, sub }))
+    } else {
+        let fail = |kind| Err(Err::Failure(ParseError::from_kind(input, kind)));
+        let (sub, size) = match defs.get(name) {
+            Ok(bound) => bound,
+            Err(kind) => return fail(kind),
+        };
+
+        let expanded = defs.expanded.get().saturating_add(size);
+        if expanded > MAX_EXPANDED {
+            return fail(ParseErrorKind::TooLarge);
+        }
+        defs.expanded.set(expanded);
+
+        let name = name.to_owned();
+        Ok((i, Expression::Reference { name, sub }))
+    }
+}
+
+pub fn separator_expression<'a>(input: &'a str) -> IResult<&'a str, Expression, ParseError<'a>> {
     use nom::branch::alt;
     use nom::bytes::complete::tag;
     use nom::combinator::map;
@@ -314,12 +899,25 @@ pub struct ParseError<'a> {
 
 #[derive(Debug, PartialEq, Clone)]
 enum ParseErrorKind {
-    UnclosedString,
+    UnclosedString(char),
     MissingDelimiter(char),
     MissingChar(char),
     UnrecognizedName,
     UnrecognizedStyle,
     InvalidRGB,
+    InvalidHex,
+    InvalidIndex,
+    NotNumeric,
+    NumberTooLarge(u32),
+    InvalidEscape,
+    InvalidUnicode,
+    MissingNumber,
+    UnrecognizedFilter,
+    InvalidRegex(String),
+    MissingIdentifier,
+    Undefined,
+    Recursive,
+    TooLarge,
     Other(error::ErrorKind),
 }
 
@@ -331,6 +929,14 @@ pub struct PrettyPrinter<'a> {
 }
 
 impl<'a> ParseError<'a> {
+    fn from_kind(input: &'a str, kind: ParseErrorKind) -> Self {
+        ParseError {
+            error: (input, kind),
+            context: None,
+            top: None,
+        }
+    }
+
     fn missing_delimiter(input: &'a str, mut other: Self, delimiter: char) -> Self {
         other.error = (input, ParseErrorKind::MissingDelimiter(delimiter));
         other
@@ -400,14 +1006,39 @@ impl<'a> PrettyPrinter<'a> {
     pub fn pretty_print(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use ParseErrorKind::*;
         match &self.error.error.1 {
-            UnclosedString => self.error_message(self.error.error.0.len(), f, |f, bold| {
-                writeln!(f, "missing closing quote ({})", bold.paint("\'"))
-            }),
+            UnclosedString(quote) => {
+                self.error_message(self.error.error.0.chars().count(), f, |f, bold| {
+                    writeln!(f, "missing closing quote ({})", bold.paint(quote))
+                })
+            }
+            InvalidEscape => {
+                let found = self.error.error.0.chars().take(2).collect::<String>();
+                self.error_message(found.chars().count(), f, |f, bold| {
+                    writeln!(
+                        f,
+                        "unknown escape \"{}\", expected one of {}",
+                        bold.paint(found.as_str()),
+                        bold.paint(r#"\n \t \u{...} \" \' \\"#)
+                    )
+                })
+            }
+            InvalidUnicode => {
+                // show interest up to the end of the escape, when it has one
+                let found = self.error.error.0;
+                let len = found.find(['}', '"']).map_or(2, |i| i + 1);
+                self.error_message(found[..len].chars().count(), f, |f, bold| {
+                    writeln!(
+                        f,
+                        "unicode escape must be in the form \"{}\" with a valid code point",
+                        bold.paint(r"\u{2714}")
+                    )
+                })
+            }
             MissingDelimiter(d) => self.error_message(1, f, |f, bold| {
                 writeln!(f, "reached end without finding matching {}", bold.paint(d))
             }),
             MissingChar(c) => {
-                let found: &str = &self.error.error.0.get(0..1).unwrap_or("");
+                let found: &str = self.error.error.0.get(0..1).unwrap_or("");
                 self.error_message(1, f, |f, bold| {
                     writeln!(
                         f,
@@ -428,23 +1059,92 @@ impl<'a> PrettyPrinter<'a> {
                 })
             }
             UnrecognizedStyle => {
-                let found: &str = &self.error.error.0.get(0..1).unwrap_or("");
+                let found: &str = self.error.error.0.get(0..1).unwrap_or("");
                 self.error_message(1, f, |f, bold| {
                     writeln!(f, "found \"{}\" which is not a style", bold.paint(found))
                 })
             }
             InvalidRGB => {
                 // find a potential matching brace and show interest up to that region
-                let found = self
-                    .error
-                    .error
-                    .0
-                    .find(|c| c == ']' || c == '}')
-                    .unwrap_or(1);
-                self.error_message(found.min(5).max(1), f, |f, bold| {
+                let found = self.error.error.0.find([']', '}']).unwrap_or(1);
+                self.error_message(found.clamp(1, 5), f, |f, bold| {
                     writeln!(f, "RGB must be in the form \"{}\"", bold.paint("0,0,0"))
                 })
             }
+            InvalidHex => {
+                let found = self.error.error.0.find([']', '}']).unwrap_or(1);
+                self.error_message(found.max(1), f, |f, bold| {
+                    writeln!(
+                        f,
+                        "hex color must be in the form \"{}\" or \"{}\"",
+                        bold.paint("#rrggbb"),
+                        bold.paint("#rgb")
+                    )
+                })
+            }
+            InvalidIndex => {
+                let found = self.error.error.0;
+                let digits = found.find(|c: char| !c.is_ascii_digit());
+                self.error_message(digits.unwrap_or(found.len()), f, |f, bold| {
+                    writeln!(f, "color index must be at most {}", bold.paint(u8::MAX))
+                })
+            }
+            NotNumeric => self.error_message(1, f, |f, _| {
+                writeln!(f, "only expressions which are numbers can be compared")
+            }),
+            NumberTooLarge(max) => {
+                let found = self.error.error.0;
+                let digits = found.find(|c: char| !c.is_ascii_digit());
+                self.error_message(digits.unwrap_or(found.len()), f, |f, bold| {
+                    writeln!(f, "number must be at most {}", bold.paint(max))
+                })
+            }
+            MissingNumber => self.error_message(1, f, |f, _| writeln!(f, "expected a number here")),
+            UnrecognizedFilter => {
+                let found: &str = self.error.error.0.get(0..1).unwrap_or("");
+                self.error_message(1, f, |f, bold| {
+                    writeln!(f, "found \"{}\" which is not a filter", bold.paint(found))
+                })
+            }
+            InvalidRegex(ref reason) => {
+                // show interest in the whole string which contains the pattern
+                let found = self.error.error.0;
+                let len = match literal_expression(found) {
+                    Ok((rest, _)) => found[..found.len() - rest.len()].chars().count(),
+                    Err(_) => 1,
+                };
+                self.error_message(len, f, |f, _| writeln!(f, "invalid pattern: {}", reason))
+            }
+            MissingIdentifier => self.error_message(1, f, |f, _| {
+                writeln!(
+                    f,
+                    "expected a name made of letters, digits, and underscores"
+                )
+            }),
+            Undefined | Recursive | TooLarge => {
+                let found = self.error.error.0;
+                // The reference is either `&name` or `&{name}`
+                let end = match found[1..].strip_prefix('{') {
+                    Some(braced) => braced.find('}').map_or(found.len(), |end| end + 3),
+                    None => found[1..]
+                        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                        .map_or(found.len(), |end| end + 1),
+                };
+                self.error_message(end, f, |f, bold| match self.error.error.1 {
+                    Recursive => writeln!(f, "\"{}\" refers to itself", bold.paint(&found[..end])),
+                    TooLarge => writeln!(
+                        f,
+                        "\"{}\" makes the format output more than {} expressions",
+                        bold.paint(&found[..end]),
+                        bold.paint(MAX_EXPANDED)
+                    ),
+                    _ => writeln!(
+                        f,
+                        "\"{}\" is not defined before here",
+                        bold.paint(&found[..end])
+                    ),
+                })
+            }
             Other(e) => self.error_message(1, f, |f, _| writeln!(f, "{:?}", e)),
         }
     }
@@ -538,7 +1238,7 @@ mod test {
         fn disp_parse_invariant(expect in arb_expression()) {
             let test = format!("{}", expect);
             println!("{} from {:?}", test, expect);
-            let parse = expression(test.as_ref());
+            let parse = expression(&Definitions::default(), test.as_ref());
             println!("\t parsed => {:?}", parse);
             let parse = parse.unwrap().1;
             println!("expect {} ==\nresult {}\n", expect, parse);
@@ -575,7 +1275,7 @@ mod test {
                 sub: Tree(vec![
                     Expression::Literal("試験".to_string()),
                     Expression::Format {
-                        style: (&[Style::Bold]).iter().collect(),
+                        style: [Style::Bold].iter().collect(),
                         sub: Tree(vec![Expression::Literal("テスト".to_string())]),
                     },
                 ]),
@@ -592,7 +1292,7 @@ mod test {
             name: Name::Stashed,
             sub: Tree::new(),
         };
-        let parse = named_expression(test).unwrap().1;
+        let parse = named_expression(&Definitions::default(), test).unwrap().1;
         assert!(parse == expect, "{:?} != {:?}", parse, expect);
     }
 
@@ -603,9 +1303,9 @@ mod test {
             name: Name::Branch,
             sub: Tree::new(),
         };
-        let parse = match named_expression(test) {
+        let parse = match named_expression(&Definitions::default(), test) {
             IResult::Ok((_, exp)) => exp,
-            fail @ _ => panic!("Failed to parse with result {:?}", fail),
+            fail => panic!("Failed to parse with result {:?}", fail),
         };
         assert!(parse == expect, "{:?} != {:?}", parse, expect);
     }
@@ -620,9 +1320,9 @@ mod test {
                 sub: Tree::new(),
             }]),
         };
-        let parse = match named_expression(test) {
+        let parse = match named_expression(&Definitions::default(), test) {
             IResult::Ok((_, exp)) => exp,
-            fail @ _ => panic!("Failed to parse with result {:?}", fail),
+            fail => panic!("Failed to parse with result {:?}", fail),
         };
         assert!(parse == expect, "{:?} != {:?}", parse, expect);
     }
@@ -643,9 +1343,9 @@ mod test {
                 },
             ]),
         };
-        let parse = match named_expression(test) {
+        let parse = match named_expression(&Definitions::default(), test) {
             IResult::Ok((_, exp)) => exp,
-            fail @ _ => panic!("Failed to parse with result {:?}", fail),
+            fail => panic!("Failed to parse with result {:?}", fail),
         };
         assert!(parse == expect, "{:?} != {:?}", parse, expect);
     }
@@ -654,7 +1354,7 @@ mod test {
     fn format_rgb() {
         let test = "#[42,0,0]{0,0,42}(bB)";
         let expect = Expression::Format {
-            style: vec![Style::Fg(RGB(42, 0, 0)), Style::Bg(RGB(0, 0, 42))]
+            style: [Style::Fg(RGB(42, 0, 0)), Style::Bg(RGB(0, 0, 42))]
                 .iter()
                 .collect(),
             sub: Tree(vec![
@@ -668,13 +1368,97 @@ mod test {
                 },
             ]),
         };
-        let parse = match format_expression(test) {
+        let parse = match format_expression(&Definitions::default(), test) {
             IResult::Ok((_, exp)) => exp,
-            fail @ _ => panic!("Failed to parse with result {:?}", fail),
+            fail => panic!("Failed to parse with result {:?}", fail),
         };
         assert!(parse == expect, "{:?} != {:?}", parse, expect);
     }
 
+    #[test]
+    fn format_text_styles() {
+        use Style::*;
+
+        let test = "#dfvx=o('x')";
+        let expect = Expression::Format {
+            style: [
+                Dim,
+                Blink,
+                Reverse,
+                Strikethrough,
+                DoubleUnderline,
+                Overline,
+            ]
+            .iter()
+            .collect(),
+            sub: Tree(vec![Expression::Literal("x".to_owned())]),
+        };
+        let parse = match format_expression(&Definitions::default(), test) {
+            IResult::Ok((_, exp)) => exp,
+            fail => panic!("Failed to parse with result {:?}", fail),
+        };
+        assert!(parse == expect, "{:?} != {:?}", parse, expect);
+        assert_eq!(format!("{}", parse), test);
+    }
+
+    #[test]
+    fn format_palette_colors() {
+        let test = "#+r(208)<16>+K(b)";
+        let expect = Expression::Format {
+            style: [
+                Style::Fg(BrightRed),
+                Style::Fg(Indexed(208)),
+                Style::Bg(Indexed(16)),
+                Style::Bg(BrightBlack),
+            ]
+            .iter()
+            .collect(),
+            sub: Tree(vec![Expression::Named {
+                name: Name::Branch,
+                sub: Tree::new(),
+            }]),
+        };
+        let parse = match format_expression(&Definitions::default(), test) {
+            IResult::Ok((_, exp)) => exp,
+            fail => panic!("Failed to parse with result {:?}", fail),
+        };
+        assert!(parse == expect, "{:?} != {:?}", parse, expect);
+
+        let kind = |format| match super::parse(format) {
+            Err(e) => e.error.1,
+            Ok(tree) => panic!("parsed {:?} from {:?}", tree, format),
+        };
+        assert_eq!(kind("#(256)(b)"), ParseErrorKind::InvalidIndex);
+        assert_eq!(kind("#<16(b)"), ParseErrorKind::MissingDelimiter('>'));
+        assert_eq!(kind("#+*(b)"), ParseErrorKind::UnrecognizedStyle);
+    }
+
+    #[test]
+    fn format_hex() {
+        let rgb = |format| match super::parse(format) {
+            Ok(tree) => match &tree.0[..] {
+                [Expression::Format { style, .. }] => (style.fg, style.bg),
+                _ => panic!("parsed {:?} from {:?}", tree, format),
+            },
+            Err(e) => panic!("failed to parse {:?}: {:?}", format, e),
+        };
+        assert_eq!(
+            rgb("#[#ffaf34]{#FA3}(b)"),
+            (Some(RGB(255, 175, 52)), Some(RGB(255, 170, 51)))
+        );
+        assert_eq!(rgb("#[#ffaf34](b)"), rgb("#[255,175,52](b)"));
+
+        let kind = |format| match super::parse(format) {
+            Err(e) => e.error.1,
+            Ok(tree) => panic!("parsed {:?} from {:?}", tree, format),
+        };
+        assert_eq!(kind("#[#ffaf3](b)"), ParseErrorKind::InvalidHex);
+        assert_eq!(kind("#{#ggg}(b)"), ParseErrorKind::InvalidHex);
+        assert_eq!(kind("#[#](b)"), ParseErrorKind::InvalidHex);
+        assert_eq!(kind("#[#fa3(b)"), ParseErrorKind::MissingDelimiter(']'));
+        assert_eq!(kind("#[300,0,0](b)"), ParseErrorKind::InvalidRGB);
+    }
+
     #[test]
     fn empty_group_expression() {
         let test = "{}\\()[]<>";
@@ -698,11 +1482,343 @@ mod test {
         ]);
         let parse = match expression_tree(test) {
             IResult::Ok((_, exp)) => exp,
-            fail @ _ => panic!("Failed to parse with result {:?}", fail),
+            fail => panic!("Failed to parse with result {:?}", fail),
         };
         assert!(parse == expect, "{:?} != {:?}", parse, expect);
     }
 
+    #[test]
+    fn conditional() {
+        use Separator::Space;
+        let test = "?(u, #r('CONFLICT'), 'ok')";
+        let expect = Expression::Conditional {
+            condition: Tree(vec![Expression::Named {
+                name: Name::Conflict,
+                sub: Tree::new(),
+            }]),
+            then: Tree(vec![
+                Expression::Separator(Space),
+                Expression::Format {
+                    style: [Style::Fg(Red)].iter().collect(),
+                    sub: Tree(vec![Expression::Literal("CONFLICT".to_string())]),
+                },
+            ]),
+            otherwise: Tree(vec![
+                Expression::Separator(Space),
+                Expression::Literal("ok".to_string()),
+            ]),
+        };
+        let parse = match conditional_expression(&Definitions::default(), test) {
+            IResult::Ok((_, exp)) => exp,
+            fail => panic!("Failed to parse with result {:?}", fail),
+        };
+        assert!(parse == expect, "{:?} != {:?}", parse, expect);
+        assert_eq!(format!("{}", parse), test);
+    }
+
+    #[test]
+    fn conditional_errors() {
+        use ParseErrorKind::*;
+
+        let kind = |test| parse(test).unwrap_err().error.1;
+        assert_eq!(kind("?(u)"), MissingChar(','));
+        assert_eq!(kind("?(u,b"), MissingDelimiter(')'));
+        assert_eq!(kind("?(u,b,B,a)"), MissingChar(')'));
+        assert_eq!(kind("?(u,z)"), UnrecognizedName);
+    }
+
+    #[test]
+    fn comparison() {
+        use crate::ast::Comparison::*;
+
+        let compare = |name, op, value, sub| Expression::Compare {
+            name,
+            op,
+            value,
+            sub,
+        };
+        let named = |name| Expression::Named {
+            name,
+            sub: Tree::new(),
+        };
+
+        assert_eq!(
+            parse("->10").unwrap(),
+            Tree(vec![compare(Name::Behind, Greater, 10, Tree::new())])
+        );
+        assert_eq!(
+            parse("?(+==0,'ok')").unwrap(),
+            Tree(vec![Expression::Conditional {
+                condition: Tree(vec![compare(Name::Ahead, Equal, 0, Tree::new())]),
+                then: Tree(vec![Expression::Literal("ok".into())]),
+                otherwise: Tree::new(),
+            }])
+        );
+        assert_eq!(
+            parse("u<3(b)").unwrap(),
+            Tree(vec![compare(
+                Name::Conflict,
+                Less,
+                3,
+                Tree(vec![named(Name::Branch)])
+            )])
+        );
+        // without a number, `<` still begins a group
+        assert_eq!(
+            parse("u<M>").unwrap(),
+            Tree(vec![
+                named(Name::Conflict),
+                Expression::Group {
+                    d: Delimiter::Angle,
+                    sub: Tree(vec![named(Name::Modified)]),
+                }
+            ])
+        );
+    }
+
+    #[test]
+    fn comparison_errors() {
+        use ParseErrorKind::*;
+
+        let kind = |test| parse(test).unwrap_err().error.1;
+        assert_eq!(kind("b>1"), NotNumeric);
+        assert_eq!(kind("o==1"), NotNumeric);
+        assert_eq!(kind("+>99999999999"), NumberTooLarge(u32::MAX));
+    }
+
+    #[test]
+    fn definition() {
+        let staged = Arc::new(Tree(vec![Expression::Named {
+            name: Name::Modified,
+            sub: Tree::new(),
+        }]));
+        let expect = Tree(vec![
+            Expression::Definition {
+                name: "staged".into(),
+                sub: Arc::clone(&staged),
+            },
+            Expression::Reference {
+                name: "staged".into(),
+                sub: Arc::clone(&staged),
+            },
+            Expression::Separator(Separator::Space),
+            Expression::Reference {
+                name: "staged".into(),
+                sub: staged,
+            },
+        ]);
+        assert_eq!(parse("&staged=(M)&staged &staged").unwrap(), expect);
+
+        // definitions can be replaced, and refer to the definition they replace
+        let tree = parse("&a=(b)&a=(&a B)&a").unwrap();
+        assert_eq!(format!("{}", tree), "&a=(b)&a=(&a B)&a");
+        assert_eq!(
+            tree.0[2],
+            Expression::Reference {
+                name: "a".into(),
+                sub: Arc::new(Tree(parse("&a=(b)&a B").unwrap().0[1..].to_vec())),
+            }
+        );
+
+        // definitions are visible after the sub-tree which defines them
+        assert!(parse("[&a=(b)]&a").is_ok());
+
+        // braces end a name before letters, digits and underscores
+        let tree = parse("&a=(b)&{a}b&{a}_&a").unwrap();
+        assert_eq!(tree.0.len(), 6);
+        assert_eq!(tree.to_string(), "&a=(b)&{a}b&{a}_&a");
+    }
+
+    #[test]
+    fn definitions_are_shared() {
+        // every reference shares the tree of its definition, rather than a copy of it
+        let mut format = "&a=(bB)".to_owned();
+        for n in 0..64 {
+            format += &format!("&a{}=(&a&a)", n);
+        }
+        let tree = parse(&format).unwrap();
+        assert_eq!(tree.to_string(), format);
+        // and names are only visited where they are defined
+        let request = crate::git::Request::from(&tree);
+        assert!(request == crate::git::Request::from(&parse("bB").unwrap()));
+        match (&tree.0[0], &tree.0[1]) {
+            (Expression::Definition { sub: a, .. }, Expression::Definition { sub, .. }) => {
+                match &sub.0[0] {
+                    Expression::Reference { sub, .. } => assert!(Arc::ptr_eq(a, sub)),
+                    other => panic!("expected reference, found {:?}", other),
+                }
+            }
+            other => panic!("expected definitions, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn trees_are_send_and_sync() {
+        fn send_sync<T: Send + Sync>(_: &T) {}
+        send_sync(&parse("&a=(b)&a").unwrap());
+    }
+
+    #[test]
+    fn definitions_are_forgotten() {
+        use ParseErrorKind::*;
+
+        // every format has definitions of its own
+        assert!(parse("&a=(b)").is_ok());
+        match expression_tree("&a") {
+            Err(nom::Err::Failure(e)) => assert_eq!(e.error, ("&a", Undefined)),
+            other => panic!("expected undefined name, found {:?}", other),
+        }
+
+        // so does an expression which fails to parse
+        let defs = Definitions::default();
+        let fails = defs.attempt(|i| -> IResult<&str, (), ParseError> {
+            binding_expression(&defs, "&a=(b)")?;
+            Err(nom::Err::Error(error::ParseError::from_error_kind(
+                i,
+                error::ErrorKind::Alt,
+            )))
+        });
+        assert!(fails("b").is_err());
+        assert!(defs.get("a") == Err(Undefined));
+    }
+
+    #[test]
+    fn definition_errors() {
+        use ParseErrorKind::*;
+
+        let kind = |test| parse(test).unwrap_err().error;
+        assert_eq!(kind("b &x"), ("&x", Undefined));
+        assert_eq!(kind("&x M &x=(M)"), ("&x M &x=(M)", Undefined));
+        assert_eq!(kind("&x=([&x])"), ("&x])", Recursive));
+        assert_eq!(kind("&=(b)"), ("=(b)", MissingIdentifier));
+        assert_eq!(kind("&x=b"), ("b", MissingChar('(')));
+
+        // each definition doubles the output of the one before
+        let doubled = |n| format!("&x=('x'){}", "&x=(&x&x)".repeat(n));
+        assert!(parse(&doubled(8)).is_ok());
+        assert_eq!(parse(&doubled(16)).unwrap_err().error.1, TooLarge);
+    }
+
+    #[test]
+    fn filter() {
+        let branch = Tree(vec![Expression::Named {
+            name: Name::Branch,
+            sub: Tree::new(),
+        }]);
+        let truncate = |width, ellipsis: &str| {
+            Tree(vec![Expression::Filter {
+                filter: Filter::Truncate {
+                    width,
+                    ellipsis: ellipsis.to_owned(),
+                },
+                sub: branch.clone(),
+            }])
+        };
+        assert_eq!(parse("%.20(b)"), Ok(truncate(20, "…")));
+        assert_eq!(parse("%.8'..'(b)"), Ok(truncate(8, "..")));
+
+        let pad = |align, width| {
+            Tree(vec![Expression::Filter {
+                filter: Filter::Pad { align, width },
+                sub: branch.clone(),
+            }])
+        };
+        assert_eq!(parse("%<20(b)"), Ok(pad(Align::Left, 20)));
+        assert_eq!(parse("%>8(b)"), Ok(pad(Align::Right, 8)));
+        assert_eq!(parse("%^0(b)"), Ok(pad(Align::Center, 0)));
+
+        let rewrite = |filter| {
+            Tree(vec![Expression::Filter {
+                filter,
+                sub: branch.clone(),
+            }])
+        };
+        let pattern = |pattern| Pattern(Regex::new(pattern).unwrap());
+        assert_eq!(parse("%U(b)"), Ok(rewrite(Filter::Upper)));
+        assert_eq!(parse("%L(b)"), Ok(rewrite(Filter::Lower)));
+        assert_eq!(parse("%T(b)"), Ok(rewrite(Filter::Title)));
+        assert_eq!(parse("%:3(b)"), Ok(rewrite(Filter::First(3))));
+        assert_eq!(
+            parse("%#'users/'(b)"),
+            Ok(rewrite(Filter::StripPrefix("users/".to_owned())))
+        );
+        assert_eq!(
+            parse("%/'[A-Z]+-\\d+'(b)"),
+            Ok(rewrite(Filter::Capture(pattern(r"[A-Z]+-\d+"))))
+        );
+        assert_eq!(
+            parse("%/'^users/'/''(b)"),
+            Ok(rewrite(Filter::Replace {
+                pattern: pattern("^users/"),
+                replacement: String::new(),
+            }))
+        );
+    }
+
+    #[test]
+    fn filter_errors() {
+        use ParseErrorKind::*;
+        let kind = |format| parse(format).unwrap_err().error.1;
+        assert_eq!(kind("%q(b)"), UnrecognizedFilter);
+        assert_eq!(kind("%.(b)"), MissingNumber);
+        assert_eq!(kind("%<(b)"), MissingNumber);
+        assert_eq!(kind("%:(b)"), MissingNumber);
+        assert_eq!(kind("%u(b)"), UnrecognizedFilter);
+        assert_eq!(kind("%.99999999999(b)"), NumberTooLarge(u32::MAX));
+        assert_eq!(kind("%>70000(b)"), NumberTooLarge(Filter::MAX_WIDTH));
+        assert_eq!(kind("%:4097(b)"), NumberTooLarge(Filter::MAX_WIDTH));
+        assert_eq!(kind("%.20'..(b)"), UnclosedString('\''));
+        assert_eq!(kind("%.20"), MissingChar('('));
+        assert_eq!(kind("%#(b)"), MissingChar('\''));
+        assert_eq!(kind("%/'a'/(b)"), MissingChar('\''));
+        assert_eq!(
+            kind("%/'(JIRA'(b)"),
+            InvalidRegex("unclosed group".to_owned())
+        );
+    }
+
+    #[test]
+    fn literal_escapes() {
+        let literals = |format| match parse(format) {
+            Ok(Tree(exps)) => exps
+                .into_iter()
+                .filter_map(|e| match e {
+                    Expression::Literal(literal) => Some(literal),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+            Err(e) => panic!("failed to parse {:?}: {:?}", format, e),
+        };
+
+        assert_eq!(
+            literals(r"'\w\n\u' '\\' 'C:\'"),
+            vec![r"\w\n\u", r"\\", r"C:\"]
+        );
+        assert_eq!(
+            literals(r#""tab\there\n" "\u{2714}\u{1F600}" "\"\'\\""#),
+            vec!["tab\there\n", "✔😀", r#""'\"#]
+        );
+        assert_eq!(
+            literals(r#""it's" 'say "hi"'"#),
+            vec!["it's", r#"say "hi""#]
+        );
+    }
+
+    #[test]
+    fn literal_escape_errors() {
+        use ParseErrorKind::*;
+        let error = |format| parse(format).unwrap_err().error;
+
+        assert_eq!(error(r#"b"ab\qc""#), (r#"\qc""#, InvalidEscape));
+        assert_eq!(error(r#""\u{zz}""#), (r#"\u{zz}""#, InvalidUnicode));
+        assert_eq!(error(r#""\u{110000}""#), (r#"\u{110000}""#, InvalidUnicode));
+        assert_eq!(error(r#""\u{}""#), (r#"\u{}""#, InvalidUnicode));
+        assert_eq!(error(r#""\u{+41}""#), (r#"\u{+41}""#, InvalidUnicode));
+        assert_eq!(error(r#""\u2714""#), (r#"\u2714""#, InvalidUnicode));
+        assert_eq!(error(r#""abc"#), ("abc", UnclosedString('"')));
+        assert_eq!(error(r"'abc"), ("abc", UnclosedString('\'')));
+    }
+
     #[test]
     fn disp() {
         let expect = "\\('quoted literal'#*(bB))";
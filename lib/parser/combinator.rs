@@ -30,7 +30,7 @@ where
     }
 }
 
-///
+/// Parse many items between a left and right delimiter, failing if the right delimiter is missing
 pub fn delimited_many0<I, O1, O2, O3, E: error::ParseError<I>, F, G, H>(
     left: F,
     item: G,
@@ -0,0 +1,413 @@
+//! Measure and transform text output by the interpreter, which may contain ANSI escapes
+//!
+//! Widths are measured in terminal columns, so wide characters like CJK take two columns, and
+//! escape sequences take none.
+
+use crate::ast::Align;
+use regex::Regex;
+use std::ops::Range;
+use unicode_width::UnicodeWidthChar;
+
+/// Part of the text output by the interpreter
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum Segment<'a> {
+    /// One or more ANSI escape sequences, which are not shown in the terminal
+    Escape(&'a str),
+    /// Text shown in the terminal
+    Text(&'a str),
+}
+
+/// Marks the start of text from a string in the format, in the output captured for a filter
+///
+/// Strings may hold prompt sequences of the shell like `'\w'`, so unlike text from the repository
+/// they are not escaped for the shell.  The markers are escape sequences, so filters keep them
+/// without counting them as text, and they are removed before the output is written.
+pub(crate) const LITERAL_START: &str = "\x1B[<1~";
+
+/// Marks the end of text from a string in the format, see [`LITERAL_START`]
+pub(crate) const LITERAL_END: &str = "\x1B[<0~";
+
+/// Iterator over the escape sequences of text and the text between them
+pub(crate) struct Segments<'a>(&'a str);
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = Segment<'a>;
+
+    fn next(&mut self) -> Option<Segment<'a>> {
+        if self.0.is_empty() {
+            return None;
+        }
+
+        let is_marker = |s: &str| s.starts_with(LITERAL_START) || s.starts_with(LITERAL_END);
+        let (segment, len): (fn(&'a str) -> Segment<'a>, _) = if is_marker(self.0) {
+            (Segment::Escape, LITERAL_START.len())
+        } else if self.0.starts_with("\x1B[") {
+            // Consecutive escape sequences form a single segment, apart from literal markers
+            let mut len = 0;
+            while let Some(sequence) = self.0[len..]
+                .strip_prefix("\x1B[")
+                .filter(|_| !is_marker(&self.0[len..]))
+            {
+                // Control sequences end with a byte from `@` to `~`
+                len += sequence
+                    .find(|c| ('@'..='~').contains(&c))
+                    .map_or(sequence.len(), |i| i + 1)
+                    + 2;
+            }
+            (Segment::Escape, len)
+        } else {
+            let end = self
+                .0
+                .char_indices()
+                .skip(1)
+                .find(|&(_, c)| c == '\x1B')
+                .map_or(self.0.len(), |(i, _)| i);
+            (Segment::Text, end)
+        };
+
+        let (next, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(segment(next))
+    }
+}
+
+/// Split text into escape sequences and the text between them
+pub(crate) fn segments(s: &str) -> Segments<'_> {
+    Segments(s)
+}
+
+fn char_width(c: char) -> usize {
+    c.width().unwrap_or(0)
+}
+
+/// Number of terminal columns the text takes, without its escape sequences
+pub(crate) fn width(s: &str) -> usize {
+    segments(s)
+        .map(|segment| match segment {
+            Segment::Escape(_) => 0,
+            Segment::Text(text) => text.chars().map(char_width).sum(),
+        })
+        .sum()
+}
+
+/// Whether the text shows anything besides escape sequences
+pub(crate) fn has_text(s: &str) -> bool {
+    segments(s).any(|segment| matches!(segment, Segment::Text(text) if !text.is_empty()))
+}
+
+/// Limit text to a number of terminal columns, ending it with the ellipsis when it is truncated
+///
+/// Every escape sequence is kept, so styles which end after the truncated text are still reset.
+/// When the ellipsis does not fit at all, the text is truncated without it.
+pub(crate) fn truncate(s: &str, columns: usize, ellipsis: &str) -> String {
+    if width(s) <= columns {
+        return s.to_owned();
+    }
+
+    let (columns, ellipsis) = match columns.checked_sub(width(ellipsis)) {
+        Some(columns) => (columns, ellipsis),
+        None => (columns, ""),
+    };
+
+    let mut truncated = String::with_capacity(s.len());
+    let mut used = 0;
+    let mut done = false;
+    for segment in segments(s) {
+        match segment {
+            Segment::Escape(sequence) => truncated.push_str(sequence),
+            Segment::Text(_) if done => {}
+            Segment::Text(text) => {
+                for c in text.chars() {
+                    used += char_width(c);
+                    if used > columns {
+                        truncated.push_str(ellipsis);
+                        done = true;
+                        break;
+                    }
+                    truncated.push(c);
+                }
+            }
+        }
+    }
+
+    truncated
+}
+
+/// Pad text with spaces to at least a number of terminal columns
+pub(crate) fn pad(s: &str, columns: usize, align: Align) -> String {
+    let padding = columns.saturating_sub(width(s));
+    let (left, right) = match align {
+        Align::Left => (0, padding),
+        Align::Right => (padding, 0),
+        Align::Center => (padding / 2, padding - padding / 2),
+    };
+
+    format!("{}{}{}", " ".repeat(left), s, " ".repeat(right))
+}
+
+/// Text without its escape sequences
+pub(crate) fn plain(s: &str) -> String {
+    segments(s)
+        .filter_map(|segment| match segment {
+            Segment::Escape(_) => None,
+            Segment::Text(text) => Some(text),
+        })
+        .collect()
+}
+
+/// Remove a prefix from the text, keeping its escape sequences
+pub(crate) fn strip_prefix(s: &str, prefix: &str) -> String {
+    if plain(s).starts_with(prefix) {
+        edit(s, vec![(0..prefix.len(), String::new())])
+    } else {
+        s.to_owned()
+    }
+}
+
+/// The first capture group of a pattern in the text, keeping its escape sequences
+///
+/// Patterns without groups capture their whole match, and text which does not match is left
+/// unchanged.
+pub(crate) fn capture(s: &str, pattern: &Regex) -> String {
+    let plain = plain(s);
+    let group = if pattern.captures_len() > 1 { 1 } else { 0 };
+    let captures = match pattern.captures(&plain) {
+        Some(captures) => captures,
+        None => return s.to_owned(),
+    };
+
+    let edits = match captures.get(group) {
+        Some(m) => vec![
+            (0..m.start(), String::new()),
+            (m.end()..plain.len(), String::new()),
+        ],
+        None => vec![(0..plain.len(), String::new())],
+    };
+    edit(s, edits)
+}
+
+/// Replace every match of a pattern in the text, keeping its escape sequences
+pub(crate) fn replace(s: &str, pattern: &Regex, replacement: &str) -> String {
+    let plain = plain(s);
+    let edits = pattern
+        .captures_iter(&plain)
+        .map(|captures| {
+            let mut replaced = String::new();
+            captures.expand(replacement, &mut replaced);
+            (captures.get(0).unwrap().range(), replaced)
+        })
+        .collect();
+    edit(s, edits)
+}
+
+/// Replace ranges of the text without its escape sequences, keeping the escape sequences
+///
+/// The ranges must be sorted and must not overlap.  Each replacement is written at the start of
+/// its range, and escape sequences inside the range follow it, so the replacement has the style
+/// of the text it replaces.
+fn edit(s: &str, edits: Vec<(Range<usize>, String)>) -> String {
+    let mut edited = String::with_capacity(s.len());
+    let mut edits = edits.into_iter().peekable();
+    let mut removed = 0..0;
+    let mut offset = 0;
+    for segment in segments(s) {
+        match segment {
+            Segment::Escape(sequence) => edited.push_str(sequence),
+            Segment::Text(text) => {
+                for c in text.chars() {
+                    while let Some((range, replacement)) =
+                        edits.next_if(|(range, _)| range.start == offset)
+                    {
+                        edited.push_str(&replacement);
+                        removed = range;
+                    }
+                    if !removed.contains(&offset) {
+                        edited.push(c);
+                    }
+                    offset += c.len_utf8();
+                }
+            }
+        }
+    }
+
+    // Replacements of empty ranges at the end of the text
+    for (_, replacement) in edits {
+        edited.push_str(&replacement);
+    }
+
+    edited
+}
+
+/// Transform the text between escape sequences, keeping the escape sequences unchanged
+fn map_text<F: FnMut(&str) -> String>(s: &str, mut f: F) -> String {
+    segments(s)
+        .map(|segment| match segment {
+            Segment::Escape(sequence) => sequence.to_owned(),
+            Segment::Text(text) => f(text),
+        })
+        .collect()
+}
+
+/// Make every letter of the text uppercase
+pub(crate) fn upper(s: &str) -> String {
+    map_text(s, str::to_uppercase)
+}
+
+/// Make every letter of the text lowercase
+pub(crate) fn lower(s: &str) -> String {
+    map_text(s, str::to_lowercase)
+}
+
+/// Make the first letter of every word uppercase and the rest lowercase, where words are
+/// separated by anything which is not a letter or a digit
+pub(crate) fn title(s: &str) -> String {
+    let mut word = false;
+    map_text(s, |text| {
+        let mut titled = String::with_capacity(text.len());
+        for c in text.chars() {
+            if word {
+                titled.extend(c.to_lowercase());
+            } else {
+                titled.extend(c.to_uppercase());
+            }
+            word = c.is_alphanumeric();
+        }
+        titled
+    })
+}
+
+/// Keep only a number of characters from the start of the text, and all of its escape sequences
+pub(crate) fn first(s: &str, count: usize) -> String {
+    let mut remaining = count;
+    map_text(s, |text| {
+        let kept = text.chars().take(remaining).collect::<String>();
+        remaining -= kept.chars().count();
+        kept
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn split_segments() {
+        use Segment::*;
+
+        let s = "\x1B[0m\x1B[38;5;208mab\x1B[0m c";
+        assert_eq!(
+            segments(s).collect::<Vec<_>>(),
+            vec![
+                Escape("\x1B[0m\x1B[38;5;208m"),
+                Text("ab"),
+                Escape("\x1B[0m"),
+                Text(" c"),
+            ]
+        );
+        assert_eq!(width(s), 4);
+        assert_eq!(width("日本語"), 6);
+        assert!(!has_text("\x1B[1m\x1B[0m"));
+
+        // Literal markers are never part of another escape segment
+        let s = format!("\x1B[1m{}\\w{}\x1B[0m", LITERAL_START, LITERAL_END);
+        assert_eq!(
+            segments(&s).collect::<Vec<_>>(),
+            vec![
+                Escape("\x1B[1m"),
+                Escape(LITERAL_START),
+                Text("\\w"),
+                Escape(LITERAL_END),
+                Escape("\x1B[0m"),
+            ]
+        );
+        assert_eq!(width(&s), 2);
+    }
+
+    #[test]
+    fn truncate_text() {
+        assert_eq!(truncate("master", 6, "…"), "master");
+        assert_eq!(truncate("feature/glitter", 8, "…"), "feature…");
+        assert_eq!(truncate("feature/glitter", 8, "..."), "featu...");
+        assert_eq!(truncate("feature/glitter", 2, "..."), "fe");
+        assert_eq!(truncate("日本語", 5, "…"), "日本…");
+        assert_eq!(truncate("日本語", 4, ""), "日本");
+        assert_eq!(
+            truncate("\x1B[32mfeature\x1B[1m/glitter\x1B[0m", 8, "…"),
+            "\x1B[32mfeature\x1B[1m…\x1B[0m"
+        );
+    }
+
+    #[test]
+    fn rewrite_text() {
+        let branch = "\x1B[32musers/alice/JIRA-1234-some-long-description\x1B[0m";
+        let regex = |pattern| Regex::new(pattern).unwrap();
+
+        assert_eq!(
+            strip_prefix(branch, "users/alice/"),
+            "\x1B[32mJIRA-1234-some-long-description\x1B[0m"
+        );
+        assert_eq!(strip_prefix("main", "users/"), "main");
+        assert_eq!(
+            capture(branch, &regex("[A-Z]+-[0-9]+")),
+            "\x1B[32mJIRA-1234\x1B[0m"
+        );
+        assert_eq!(capture(branch, &regex("/([A-Z]+)-")), "\x1B[32mJIRA\x1B[0m");
+        assert_eq!(capture("main", &regex("[A-Z]+-[0-9]+")), "main");
+        assert_eq!(
+            replace(branch, &regex("^users/[^/]+/([A-Z]+-[0-9]+).*"), "$1"),
+            "\x1B[32mJIRA-1234\x1B[0m"
+        );
+        assert_eq!(replace("a-b-c", &regex("-"), "/"), "a/b/c");
+        assert_eq!(replace("ab", &regex("x*"), "-"), "-a-b-");
+    }
+
+    #[test]
+    fn rewrite_styled_text() {
+        let s = "\x1B[31musers/\x1B[0m\x1B[32mJIRA-1234\x1B[0m \x1B[34morigin\x1B[0m";
+        let regex = |pattern| Regex::new(pattern).unwrap();
+
+        assert_eq!(
+            strip_prefix(s, "users/"),
+            "\x1B[31m\x1B[0m\x1B[32mJIRA-1234\x1B[0m \x1B[34morigin\x1B[0m"
+        );
+        assert_eq!(
+            capture(s, &regex("([A-Z]+)-")),
+            "\x1B[31m\x1B[0m\x1B[32mJIRA\x1B[0m\x1B[34m\x1B[0m"
+        );
+        assert_eq!(
+            replace(s, &regex("[0-9]+"), "#$0"),
+            "\x1B[31musers/\x1B[0m\x1B[32mJIRA-#1234\x1B[0m \x1B[34morigin\x1B[0m"
+        );
+        // Replacements have the style of the start of their match
+        assert_eq!(
+            replace(s, &regex("/J"), "!"),
+            "\x1B[31musers!\x1B[0m\x1B[32mIRA-1234\x1B[0m \x1B[34morigin\x1B[0m"
+        );
+    }
+
+    #[test]
+    fn transform_text() {
+        let s = "\x1B[32mrebase-\x1B[1mINTERACTIVE\x1B[0m";
+        assert_eq!(upper(s), "\x1B[32mREBASE-\x1B[1mINTERACTIVE\x1B[0m");
+        assert_eq!(lower(s), "\x1B[32mrebase-\x1B[1minteractive\x1B[0m");
+        assert_eq!(title(s), "\x1B[32mRebase-\x1B[1mInteractive\x1B[0m");
+        assert_eq!(title("feature/add_a2b c"), "Feature/Add_A2b C");
+        assert_eq!(upper("straße"), "STRASSE");
+        assert_eq!(first(s, 3), "\x1B[32mreb\x1B[1m\x1B[0m");
+        assert_eq!(first(s, 9), "\x1B[32mrebase-\x1B[1mIN\x1B[0m");
+        assert_eq!(first("日本語", 2), "日本");
+    }
+
+    #[test]
+    fn pad_text() {
+        assert_eq!(pad("main", 8, Align::Left), "main    ");
+        assert_eq!(pad("main", 8, Align::Right), "    main");
+        assert_eq!(pad("main", 7, Align::Center), " main  ");
+        assert_eq!(pad("master", 4, Align::Center), "master");
+        assert_eq!(pad("日本", 6, Align::Right), "  日本");
+        assert_eq!(
+            pad("\x1B[32mmain\x1B[0m", 6, Align::Left),
+            "\x1B[32mmain\x1B[0m  "
+        );
+    }
+}
@@ -20,10 +20,15 @@ extern crate yansi;
 
 use git2::Repository;
 use std::fmt::{self, Display};
-use std::path::PathBuf;
+use std::io::{self, IsTerminal, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
-use glitter_lang::{git, glitter};
+use glitter_lang::config::{Config, GitConfig};
+use glitter_lang::init::{self, Prompt, Shell};
+use glitter_lang::interpreter::{ColorChoice, ColorDepth, Escapes, Interpreter};
+use glitter_lang::{git, parser::parse};
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "glit")]
@@ -33,7 +38,14 @@ use glitter_lang::{git, glitter};
 /// unstaged changes, and more.
 struct Opt {
     /// Format used in git repositories
-    git_format: String,
+    ///
+    /// Required unless running a subcommand, or a format is configured in
+    /// `$XDG_CONFIG_HOME/glit/config.toml`
+    git_format: Option<String>,
+
+    /// Use a format defined in the presets of the configuration file
+    #[structopt(long = "preset", conflicts_with = "git_format")]
+    preset: Option<String>,
 
     /// Format used outside git repositories
     #[structopt(short = "e", long = "else-format")]
@@ -41,17 +53,121 @@ struct Opt {
 
     /// Ignore syntax errors
     #[structopt(long = "silent")]
+    // Accepted so existing prompts keep working, but not honored yet
+    #[allow(dead_code)]
     silent_mode: bool,
 
-    /// Escape format characters for bash shell prompts
+    /// Escape format characters for bash shell prompts, same as `--shell bash`
     ///
-    /// Without the escapes, BASH prompt has broken line wrapping
-    #[structopt(long = "bash-escapes", short)]
+    /// Without the escapes, BASH prompt has broken line wrapping.  Use
+    /// `--shell none` to turn off escapes set in the configuration file
+    #[structopt(long = "bash-escapes", short, conflicts_with = "shell")]
     bash_escapes: bool,
 
+    /// Escape format characters for the prompt of a shell
+    ///
+    /// Marks escape sequences as non-printing so the shell wraps lines
+    /// correctly, and escapes characters in branch names which the shell
+    /// would expand in its prompt
+    #[structopt(
+        long = "shell",
+        raw(possible_values = "&Escapes::NAMES", case_insensitive = "true")
+    )]
+    shell: Option<Escapes>,
+
+    /// Colors the terminal is able to show: truecolor, 256, 16, or none
+    ///
+    /// RGB colors are approximated by the nearest color the terminal can
+    /// show.  Detected from the COLORTERM and TERM variables by default
+    #[structopt(
+        long = "color-depth",
+        raw(possible_values = "&ColorDepth::NAMES", case_insensitive = "true")
+    )]
+    color_depth: Option<ColorDepth>,
+
+    /// When to use colors: always (the default), auto, or never
+    ///
+    /// With auto, colors are used when the output is shown in a terminal,
+    /// or in a prompt with --shell, unless NO_COLOR is set
+    #[structopt(
+        long = "color",
+        raw(possible_values = "&ColorChoice::NAMES", case_insensitive = "true")
+    )]
+    color: Option<ColorChoice>,
+
+    /// Stop reading stats from the repository after this many milliseconds
+    ///
+    /// Stats which could not be read in time are left empty; use `!` in the
+    /// format to show when stats are incomplete
+    #[structopt(long = "timeout")]
+    timeout: Option<u64>,
+
+    /// Read stats from `git status --porcelain=v2 --branch` output on stdin
+    ///
+    /// Uses the git format without looking for a repository
+    #[structopt(long = "porcelain-stdin", conflicts_with = "stats_file")]
+    porcelain_stdin: bool,
+
+    /// Read stats from a JSON or TOML file instead of a repository
+    ///
+    /// Files ending in `.toml` are read as TOML, anything else as JSON.
+    /// Capture the stats of a real repository with `glit stats`.
+    #[structopt(long = "stats-file", parse(from_os_str))]
+    stats_file: Option<PathBuf>,
+
+    /// Prefer formats set in the `glit` section of the repository's git config
+    ///
+    /// Reads `glit.format` and `glit.elseFormat`, and treats the repository
+    /// as if it were not a git repository when `glit.disable` is true
+    #[structopt(long = "respect-repo-config")]
+    respect_repo_config: bool,
+
     /// Path to the git repository represented by the format
     #[structopt(long, short, parse(from_os_str), default_value = ".")]
     path: PathBuf,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Serve stats to other invocations of glit from a background process
+    ///
+    /// The daemon caches the stats of each repository it is asked about and
+    /// only reads them again when files in the repository change.  Other
+    /// invocations of glit ask the daemon for stats first, and read them
+    /// directly from the repository when no daemon is running.
+    #[structopt(name = "daemon")]
+    Daemon,
+    /// Print the stats of the repository as TOML, or JSON with --json
+    ///
+    /// The output can be edited and then used in place of a repository with
+    /// --stats-file, to preview formats against any state.
+    #[structopt(name = "stats")]
+    Stats {
+        /// Print JSON instead of TOML
+        #[structopt(long = "json")]
+        json: bool,
+    },
+    /// Print a script which sets the prompt of a shell to the output of glit
+    ///
+    /// Without a format, the formats of the configuration file are used.
+    /// For example, add `eval "$(glit init bash)"` to ~/.bashrc.
+    #[structopt(name = "init")]
+    Init {
+        /// Shell to print the script for
+        #[structopt(raw(possible_values = "&Shell::NAMES", case_insensitive = "true"))]
+        shell: Shell,
+        /// Format used in git repositories
+        git_format: Option<String>,
+        /// Format used outside git repositories
+        #[structopt(short = "e", long = "else-format")]
+        else_format: Option<String>,
+        /// Set the prompt on the right side of the line instead
+        #[structopt(long = "right")]
+        right: bool,
+    },
 }
 
 #[derive(Debug)]
@@ -59,6 +175,18 @@ enum Error {
     Git(git2::Error),
     MissingFormat(PathBuf),
     Glitter(String),
+    Daemon(std::io::Error),
+    Io(std::io::Error),
+    Porcelain(git::PorcelainError),
+    StatsFile(PathBuf, String),
+    Config(glitter_lang::config::ConfigError),
+    NoRightPrompt(Shell),
+}
+
+impl From<glitter_lang::config::ConfigError> for Error {
+    fn from(e: glitter_lang::config::ConfigError) -> Self {
+        Error::Config(e)
+    }
 }
 
 impl From<git2::Error> for Error {
@@ -78,39 +206,166 @@ impl Display for Error {
                 p.to_string_lossy()
             ),
             Glitter(e) => write!(f, "{}", e),
+            Daemon(e) => write!(f, "Daemon error: {}", e),
+            Io(e) => write!(f, "IO error: {}", e),
+            Porcelain(e) => write!(f, "Porcelain error: {}", e),
+            StatsFile(p, e) => write!(
+                f,
+                "Unable to read stats from `{}`: {}",
+                p.to_string_lossy(),
+                e
+            ),
+            Config(e) => write!(f, "Config error: {}", e),
+            NoRightPrompt(shell) => write!(f, "{} does not have a right prompt", shell),
         }
     }
 }
 
+/// Where the stats represented by the format come from
+enum Source {
+    /// Stats which were read up front, without a repository
+    Stats(git::Stats),
+    /// Stats which will be read from a repository
+    Repository(Repository),
+    /// No git repository was found
+    None,
+}
+
 fn run() -> Result<(), Error> {
     #[allow(unused)]
-    let mut color = true;
+    let mut terminal_color = true;
 
     #[cfg(windows)]
     {
         use yansi::Paint;
-        color = Paint::enable_windows_ascii();
+        terminal_color = Paint::enable_windows_ascii();
     }
 
     let opt = Opt::from_args();
 
-    // Get a format and stats from the git repository or exit early with an error
-    let (stats, format) = Repository::discover(opt.path.clone())
-        .map(|mut repo| (git::Stats::new(&mut repo), opt.git_format.clone()))
-        // if no repository is found, use the alt format if it exists
-        .or_else(|_| {
-            if let Some(format) = opt.else_format.clone() {
-                Ok((git::Stats::default(), format))
-            } else {
-                Err(Error::MissingFormat(opt.path.clone()))
+    match opt.command {
+        Some(Command::Daemon) => return daemon(),
+        Some(Command::Stats { json }) => return print_stats(&opt, json),
+        Some(Command::Init {
+            shell,
+            ref git_format,
+            ref else_format,
+            right,
+        }) => {
+            let mut args = Vec::new();
+            args.extend(git_format.as_deref());
+            if let Some(else_format) = else_format {
+                args.extend(&["-e", else_format]);
             }
-        })?;
+            let prompt = if right { Prompt::Right } else { Prompt::Left };
+            let script = init::script(shell, prompt, &args).ok_or(Error::NoRightPrompt(shell))?;
+            print!("{}", script);
+            return Ok(());
+        }
+        None => {}
+    }
+
+    // Only formatting reads the configuration, so a broken one does not break the subcommands
+    let config = Config::load()?;
+    let mut out = io::BufWriter::with_capacity(128, io::stdout());
+
+    let source = if let Some(path) = &opt.stats_file {
+        Source::Stats(read_stats_file(path)?)
+    } else if opt.porcelain_stdin {
+        let mut status = String::new();
+        std::io::stdin()
+            .read_to_string(&mut status)
+            .map_err(Error::Io)?;
+        Source::Stats(git::Stats::from_porcelain(&status).map_err(Error::Porcelain)?)
+    } else {
+        Repository::discover(opt.path.clone()).map_or(Source::None, Source::Repository)
+    };
+
+    let git_config = match &source {
+        Source::Repository(repo) if opt.respect_repo_config => GitConfig::from_repository(repo)?,
+        Source::None if opt.respect_repo_config => GitConfig::from_default()?,
+        _ => GitConfig::default(),
+    };
+    let source = if git_config.disable {
+        Source::None
+    } else {
+        source
+    };
+
+    // Get a format for the source of the stats or exit early with an error
+    let format = match source {
+        Source::Stats(_) | Source::Repository(_) => {
+            let format = git_config.format.or_else(|| opt.git_format.clone());
+            let configured = match &format {
+                Some(_) => None,
+                None => {
+                    let workdir = match &source {
+                        Source::Repository(repo) => repo.workdir(),
+                        _ => None,
+                    };
+                    config.git_format(opt.preset.as_deref(), workdir)?
+                }
+            };
+            format.or(configured).unwrap_or_else(|| {
+                use structopt::clap::{Error, ErrorKind};
+                Error::with_description(
+                    "The following required arguments were not provided:\n    <git-format>",
+                    ErrorKind::MissingRequiredArgument,
+                )
+                .exit()
+            })
+        }
+        // if no repository is found, use the alt format if it exists
+        Source::None => git_config
+            .else_format
+            .or_else(|| opt.else_format.clone())
+            .or_else(|| config.else_format.clone())
+            .ok_or_else(|| Error::MissingFormat(opt.path.clone()))?,
+    };
+    let bash = |bash_escapes| {
+        if bash_escapes {
+            Some(Escapes::Bash)
+        } else {
+            None
+        }
+    };
+    let escapes = opt
+        .shell
+        .or_else(|| bash(opt.bash_escapes))
+        .or(config.shell)
+        .or_else(|| bash(config.bash_escapes))
+        .unwrap_or_default();
+    let color_depth = opt
+        .color_depth
+        .or(config.color_depth)
+        .unwrap_or_else(ColorDepth::from_env);
+    let timeout = opt.timeout.or(config.timeout);
+
+    // Prompts capture the output, so it is not a terminal, but they show it in one
+    let choice = opt.color.or(config.color).unwrap_or_default();
+    let color =
+        terminal_color && choice.use_color(io::stdout().is_terminal() || escapes != Escapes::None);
+    let error_color = terminal_color && choice.use_color(io::stderr().is_terminal());
+
+    let result = parse(&format)
+        .map_err(glitter_lang::Error::from)
+        .and_then(|tree| {
+            // Only read the stats which the format refers to
+            let request = git::Request::from(&tree);
+            let deadline = timeout.map(|ms| Instant::now() + Duration::from_millis(ms));
+            let stats = match source {
+                Source::Stats(stats) => stats,
+                Source::Repository(repo) => read_stats(repo, &request, deadline),
+                Source::None => git::Stats::default(),
+            };
 
-    use std::io::BufWriter;
-    let mut out = BufWriter::with_capacity(128, std::io::stdout());
+            Interpreter::new(stats, color, escapes)
+                .with_color_depth(color_depth)
+                .evaluate(&tree, &mut out)
+                .map_err(glitter_lang::Error::from)
+        });
 
-    glitter(stats, &format, color, opt.bash_escapes, &mut out)
-        .map_err(|e| Error::Glitter(e.pretty_print(color)))?;
+    result.map_err(|e| Error::Glitter(e.pretty_print(error_color)))?;
 
     out.into_inner()
         .expect("Unable to complete writing format to output");
@@ -119,6 +374,82 @@ fn run() -> Result<(), Error> {
     Ok(())
 }
 
+/// Print every stat of the repository in a format readable by --stats-file
+fn print_stats(opt: &Opt, json: bool) -> Result<(), Error> {
+    let repo = Repository::discover(opt.path.clone())?;
+    let deadline = opt
+        .timeout
+        .map(|ms| Instant::now() + Duration::from_millis(ms));
+    let stats = read_stats(repo, &git::Request::all(), deadline);
+
+    if json {
+        let json = serde_json::to_string_pretty(&stats).expect("stats are serializable");
+        println!("{}", json);
+    } else {
+        print!(
+            "{}",
+            toml::to_string(&stats).expect("stats are serializable")
+        );
+    }
+
+    Ok(())
+}
+
+/// Read stats from a JSON or TOML file, depending on its extension
+fn read_stats_file(path: &Path) -> Result<git::Stats, Error> {
+    let error = |e: String| Error::StatsFile(path.to_owned(), e);
+
+    let contents = std::fs::read_to_string(path).map_err(|e| error(e.to_string()))?;
+    if path.extension().is_some_and(|ext| ext == "toml") {
+        toml::from_str(&contents).map_err(|e| error(e.to_string()))
+    } else {
+        serde_json::from_str(&contents).map_err(|e| error(e.to_string()))
+    }
+}
+
+/// Read the requested stats, from the daemon if one is running
+fn read_stats(
+    mut repo: Repository,
+    request: &git::Request,
+    deadline: Option<Instant>,
+) -> git::Stats {
+    #[cfg(unix)]
+    {
+        use glitter_lang::daemon;
+
+        // A daemon which does not answer must leave time to read the stats directly
+        let timeout = deadline.map_or(daemon::QUERY_TIMEOUT, |d| {
+            let remaining = d.saturating_duration_since(Instant::now());
+            daemon::QUERY_TIMEOUT.min(remaining / 2)
+        });
+        if let Ok(stats) = daemon::query(&daemon::socket_path(), &repo, request, timeout) {
+            return stats;
+        }
+    }
+
+    match deadline {
+        Some(deadline) => git::Stats::with_deadline(&repo, request, deadline),
+        None => git::Stats::with_request(&mut repo, request),
+    }
+}
+
+#[cfg(unix)]
+fn daemon() -> Result<(), Error> {
+    use glitter_lang::daemon;
+
+    daemon::serve(&daemon::socket_path()).map_err(Error::Daemon)
+}
+
+#[cfg(not(unix))]
+fn daemon() -> Result<(), Error> {
+    Err(Error::Daemon(io::Error::new(
+        io::ErrorKind::Other,
+        "the daemon is only supported on unix platforms",
+    )))
+}
+
+// `setup_panic!` expands to the deprecated `PanicInfo` alias on newer toolchains
+#[allow(deprecated)]
 fn main() {
     setup_panic!();
 